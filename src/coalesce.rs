@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use fsevent_sys::core_foundation as cf;
+
+use crate::runloop::CFAbsoluteTimeGetCurrent;
+use crate::runloop::CFRunLoopAddTimer;
+use crate::runloop::CFRunLoopCopyCurrentMode;
+use crate::runloop::CFRunLoopTimerContext;
+use crate::runloop::CFRunLoopTimerCreate;
+use crate::runloop::CFRunLoopTimerInvalidate;
+use crate::runloop::CFRunLoopTimerRef;
+use crate::Event;
+
+/// Merges events for the same path until a time window ends,
+/// see [`FsEventWatcher::set_coalescing_window`](crate::FsEventWatcher::set_coalescing_window).
+pub(crate) struct Coalescer {
+    window: Duration,
+    pending: Mutex<Pending>,
+}
+
+#[derive(Default)]
+struct Pending {
+    /// Merged events, in the order their paths were first seen in the window.
+    events: Vec<Event>,
+    /// Index in `events` of the event for each path.
+    index: HashMap<PathBuf, usize>,
+    /// Timer ending the window, if there are pending events.
+    timer: Option<CFRunLoopTimerRef>,
+}
+
+impl Coalescer {
+    pub(crate) fn new(window: Duration) -> Coalescer {
+        Coalescer {
+            window,
+            pending: Mutex::new(Pending::default()),
+        }
+    }
+
+    /// Merge `event` into the pending event for its path. The first event of a window
+    /// schedules a timer calling `fired` with `info` on the current run loop,
+    /// in the mode it runs in, so this must be called from the stream callback.
+    ///
+    /// Returns whether the event was merged into an already pending one.
+    pub(crate) unsafe fn push(
+        &self,
+        event: Event,
+        fired: extern "C" fn(CFRunLoopTimerRef, *mut libc::c_void),
+        info: *mut libc::c_void,
+    ) -> bool {
+        let mut pending = self.pending.lock().expect("lock not to be poisoned");
+        let merged = match pending.index.get(&event.path) {
+            Some(&index) => {
+                merge(&mut pending.events[index], event);
+                true
+            }
+            None => {
+                let index = pending.events.len();
+                pending.index.insert(event.path.clone(), index);
+                pending.events.push(event);
+                false
+            }
+        };
+        if pending.timer.is_some() {
+            return merged;
+        }
+        let mut context = CFRunLoopTimerContext {
+            version: 0,
+            info,
+            retain: None,
+            release: None,
+            copy_description: None,
+        };
+        let timer = CFRunLoopTimerCreate(
+            cf::kCFAllocatorDefault,
+            CFAbsoluteTimeGetCurrent() + self.window.as_secs_f64(),
+            0.0,
+            0,
+            0,
+            fired,
+            &mut context,
+        );
+        let runloop = cf::CFRunLoopGetCurrent();
+        let mode = CFRunLoopCopyCurrentMode(runloop);
+        if mode.is_null() {
+            CFRunLoopAddTimer(runloop, timer, cf::kCFRunLoopDefaultMode);
+        } else {
+            CFRunLoopAddTimer(runloop, timer, mode);
+            cf::CFRelease(mode);
+        }
+        pending.timer = Some(timer);
+        merged
+    }
+
+    /// Take the pending events, ending the window.
+    pub(crate) fn take(&self) -> Vec<Event> {
+        let mut pending = self.pending.lock().expect("lock not to be poisoned");
+        if let Some(timer) = pending.timer.take() {
+            unsafe {
+                CFRunLoopTimerInvalidate(timer);
+                cf::CFRelease(timer);
+            }
+        }
+        pending.index.clear();
+        mem::take(&mut pending.events)
+    }
+}
+
+impl Drop for Coalescer {
+    fn drop(&mut self) {
+        // Invalidates the timer, which must not fire after the stream context is gone.
+        self.take();
+    }
+}
+
+/// Merge a later event for the same path into `pending`.
+fn merge(pending: &mut Event, event: Event) {
+    pending.flags |= event.flags;
+    pending.raw_flags |= event.raw_flags;
+    pending.id = event.id;
+    pending.data_change = event.data_change.or(pending.data_change);
+    pending.metadata_change = event.metadata_change.or(pending.metadata_change);
+    pending.inode = event.inode.or(pending.inode);
+    pending.collapsed += event.collapsed + 1;
+    pending.batch = event.batch;
+}
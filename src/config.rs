@@ -0,0 +1,24 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Configuration of a [`FsEventWatcher`](crate::FsEventWatcher) which can be persisted
+/// across restarts, see [`to_config`](crate::FsEventWatcher::to_config) and
+/// [`from_config`](crate::FsEventWatcher::from_config).
+///
+/// Serializable with the `serde` feature. Only settings describing what is watched
+/// are included, handlers and tuning options like sampling are set up by the application.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WatcherConfig {
+    /// Watched paths, as passed to [`watch`](crate::FsEventWatcher::watch).
+    pub paths: Vec<PathBuf>,
+    pub latency: Duration,
+    /// `kFSEventStreamCreateFlag*` flags of the stream.
+    pub create_flags: u32,
+    /// Patterns added by [`add_ignore_glob`](crate::FsEventWatcher::add_ignore_glob).
+    pub ignore_globs: Vec<String>,
+    pub include_paths: Vec<PathBuf>,
+    /// Event id to replay events after, the last processed event id when taken
+    /// from a watcher which processed events.
+    pub since_when: u64,
+}
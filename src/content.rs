@@ -0,0 +1,167 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io;
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::StreamFlags;
+
+/// Hashes of file contents, used to drop modifications which did not change the content,
+/// see [`FsEventWatcher::set_content_hashing`](crate::FsEventWatcher::set_content_hashing).
+pub(crate) struct ContentHashes {
+    capacity: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Content of each file and the tick of its last use.
+    hashes: HashMap<PathBuf, (Content, u64)>,
+    /// Files by the tick of their last use, least recently used first.
+    lru: BTreeMap<u64, PathBuf>,
+    tick: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Content {
+    hash: u64,
+    /// Permission bits, which also change `INODE_META_MOD` files.
+    mode: u32,
+}
+
+impl ContentHashes {
+    pub(crate) fn new(capacity: usize) -> ContentHashes {
+        ContentHashes {
+            capacity,
+            state: Mutex::new(State {
+                hashes: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+
+    /// Whether the event is a modification or a timestamp change
+    /// which left the file content and permissions unchanged.
+    ///
+    /// Updates the stored hash for any event about a file.
+    pub(crate) fn is_unchanged(&self, path: &Path, flags: StreamFlags) -> bool {
+        if !flags.contains(StreamFlags::IS_FILE) {
+            return false;
+        }
+        let mut state = self.state.lock().expect("lock not to be poisoned");
+        let content = match read_content(path) {
+            Ok(content) => content,
+            Err(_) => {
+                // Removed, renamed away, or unreadable.
+                if let Some((_, tick)) = state.hashes.remove(path) {
+                    state.lru.remove(&tick);
+                }
+                return false;
+            }
+        };
+        state.tick += 1;
+        let tick = state.tick;
+        let previous = state.hashes.insert(path.to_owned(), (content, tick));
+        if let Some((_, previous_tick)) = previous {
+            state.lru.remove(&previous_tick);
+        }
+        state.lru.insert(tick, path.to_owned());
+        if state.hashes.len() > self.capacity {
+            // Evict the least recently used entry, which is the new one with zero capacity.
+            let (_, oldest) = state.lru.pop_first().expect("not empty");
+            state.hashes.remove(&oldest);
+        }
+        // Only modifications and timestamp changes (e.g. `touch`) are dropped: creation,
+        // removal, rename or other metadata changes are reported regardless of content.
+        let content_only = (flags
+            - (StreamFlags::ITEM_MODIFIED
+                | StreamFlags::INODE_META_MOD
+                | StreamFlags::IS_FILE
+                | StreamFlags::IS_HARDLINK
+                | StreamFlags::IS_LAST_HARDLINK))
+            .is_empty();
+        content_only && previous.map(|(previous, _)| previous) == Some(content)
+    }
+}
+
+fn read_content(path: &Path) -> io::Result<Content> {
+    let mut file = File::open(path)?;
+    let mode = file.metadata()?.mode();
+    let mut hasher = DefaultHasher::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(Content {
+                hash: hasher.finish(),
+                mode,
+            });
+        }
+        hasher.write(&buf[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use filetime::FileTime;
+
+    use super::*;
+
+    /// Empty directory unique to the test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("fsevent-dump-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn touch_without_content_change_is_unchanged() {
+        let dir = temp_dir("content-touch");
+        let path = dir.join("file");
+        fs::write(&path, b"one").unwrap();
+        let hashes = ContentHashes::new(16);
+        let modified = StreamFlags::ITEM_MODIFIED | StreamFlags::IS_FILE;
+        let touched = StreamFlags::INODE_META_MOD | StreamFlags::IS_FILE;
+
+        // Nothing to compare the first event with.
+        assert!(!hashes.is_unchanged(&path, modified));
+
+        filetime::set_file_mtime(&path, FileTime::from_unix_time(1_000_000, 0)).unwrap();
+        assert!(hashes.is_unchanged(&path, touched));
+
+        fs::write(&path, b"two").unwrap();
+        assert!(!hashes.is_unchanged(&path, modified));
+
+        fs::write(&path, b"two").unwrap();
+        assert!(hashes.is_unchanged(&path, modified));
+
+        // Creation is reported even if the content is the same.
+        assert!(!hashes.is_unchanged(&path, modified | StreamFlags::ITEM_CREATED));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn zero_capacity_keeps_nothing() {
+        let dir = temp_dir("content-zero");
+        let path = dir.join("file");
+        fs::write(&path, b"one").unwrap();
+        let hashes = ContentHashes::new(0);
+        let modified = StreamFlags::ITEM_MODIFIED | StreamFlags::IS_FILE;
+
+        assert!(!hashes.is_unchanged(&path, modified));
+        assert!(!hashes.is_unchanged(&path, modified));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
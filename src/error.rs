@@ -0,0 +1,169 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Kind of [`Error`].
+#[derive(Debug)]
+pub enum ErrorKind {
+    /// Error with a description, e.g. from CoreFoundation.
+    Generic(String),
+    /// Path to watch does not exist.
+    PathNotFound,
+    /// Operation requires a running stream.
+    NotRunning,
+    /// `FSEventStreamStart` failed, even after retries.
+    StreamStartFailed,
+    /// Too many paths for a single FSEvents stream.
+    ///
+    /// Watching a common ancestor of the paths instead, with
+    /// [`set_include_paths`](crate::FsEventWatcher::set_include_paths) to filter events,
+    /// avoids the limit.
+    MaxPathsExceeded,
+    /// No event arrived in time.
+    Timeout,
+    /// The stream started, but FSEvents does not deliver events for the watched paths,
+    /// see [`verify_delivery`](crate::FsEventWatcher::verify_delivery).
+    PermissionDenied,
+    /// I/O error other than a missing path.
+    Io(io::Error),
+}
+
+/// Error returned by [`FsEventWatcher`](crate::FsEventWatcher).
+#[derive(Debug)]
+pub struct Error {
+    pub kind: ErrorKind,
+    /// Paths related to the error.
+    pub paths: Vec<PathBuf>,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn new(kind: ErrorKind) -> Error {
+        Error {
+            kind,
+            paths: Vec::new(),
+        }
+    }
+
+    pub fn generic(description: String) -> Error {
+        Error::new(ErrorKind::Generic(description))
+    }
+
+    pub fn path_not_found() -> Error {
+        Error::new(ErrorKind::PathNotFound)
+    }
+
+    pub fn not_running() -> Error {
+        Error::new(ErrorKind::NotRunning)
+    }
+
+    pub fn stream_start_failed() -> Error {
+        Error::new(ErrorKind::StreamStartFailed)
+    }
+
+    pub fn max_paths_exceeded() -> Error {
+        Error::new(ErrorKind::MaxPathsExceeded)
+    }
+
+    pub fn timeout() -> Error {
+        Error::new(ErrorKind::Timeout)
+    }
+
+    pub fn permission_denied() -> Error {
+        Error::new(ErrorKind::PermissionDenied)
+    }
+
+    pub fn add_path(mut self, path: PathBuf) -> Error {
+        self.paths.push(path);
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ErrorKind::Generic(description) => write!(f, "{}", description)?,
+            ErrorKind::PathNotFound => write!(f, "No path was found")?,
+            ErrorKind::NotRunning => write!(f, "Watcher is not running")?,
+            ErrorKind::StreamStartFailed => write!(f, "Unable to start FSEvents stream")?,
+            ErrorKind::MaxPathsExceeded => write!(f, "Too many paths for a single stream")?,
+            ErrorKind::Timeout => write!(f, "Timed out waiting for an event")?,
+            ErrorKind::PermissionDenied => write!(f, "FSEvents does not deliver events")?,
+            ErrorKind::Io(err) => write!(f, "{}", err)?,
+        }
+        if !self.paths.is_empty() {
+            write!(f, " about {:?}", self.paths)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ErrorKind::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        match err.kind() {
+            io::ErrorKind::NotFound => Error::path_not_found(),
+            _ => Error::new(ErrorKind::Io(err)),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        let kind = match &err.kind {
+            ErrorKind::Io(io_err) => io_err.kind(),
+            ErrorKind::PathNotFound => io::ErrorKind::NotFound,
+            ErrorKind::Timeout => io::ErrorKind::TimedOut,
+            ErrorKind::PermissionDenied => io::ErrorKind::PermissionDenied,
+            ErrorKind::Generic(_)
+            | ErrorKind::NotRunning
+            | ErrorKind::StreamStartFailed
+            | ErrorKind::MaxPathsExceeded => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_error_round_trip() {
+        let err = Error::from(io::Error::new(io::ErrorKind::NotFound, "missing"));
+        assert!(matches!(err.kind, ErrorKind::PathNotFound));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::NotFound);
+
+        let err = Error::from(io::Error::new(io::ErrorKind::PermissionDenied, "denied"));
+        assert!(matches!(err.kind, ErrorKind::Io(_)));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            io::Error::from(Error::timeout()).kind(),
+            io::ErrorKind::TimedOut
+        );
+        assert_eq!(
+            io::Error::from(Error::not_running()).kind(),
+            io::ErrorKind::Other
+        );
+    }
+
+    #[test]
+    fn io_error_keeps_error() {
+        let io_err = io::Error::from(Error::not_running().add_path(PathBuf::from("/a")));
+        let err = io_err
+            .get_ref()
+            .and_then(|err| err.downcast_ref::<Error>())
+            .unwrap();
+        assert!(matches!(err.kind, ErrorKind::NotRunning));
+        assert_eq!(err.paths, [PathBuf::from("/a")]);
+    }
+}
@@ -0,0 +1,753 @@
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::slice;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use crossbeam_channel::Receiver;
+use crossbeam_channel::Sender;
+use crossbeam_channel::TrySendError;
+
+use crate::StreamFlags;
+
+/// A single event reported by FSEvents.
+///
+/// Two events are equal if all of their fields are equal, including the event id,
+/// so repeated reports of the same change are different events.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Event {
+    /// Path as reported by FSEvents, with symlinks resolved,
+    /// see [`fsevents_path`](crate::fsevents_path).
+    pub path: PathBuf,
+    pub flags: StreamFlags,
+    /// Flags as reported by FSEvents, including flags unknown to [`StreamFlags`],
+    /// see [`unknown_flags`](Event::unknown_flags).
+    pub raw_flags: u32,
+    pub id: u64,
+    /// What kind of data change an `ITEM_MODIFIED` event is,
+    /// only set when size tracking is enabled on the watcher.
+    pub data_change: Option<DataChange>,
+    /// What kind of metadata change an `ITEM_CHANGE_OWNER` or `INODE_META_MOD` event is,
+    /// only set when permission tracking is enabled on the watcher,
+    /// see [`set_track_permission_changes`](crate::FsEventWatcher::set_track_permission_changes).
+    pub metadata_change: Option<MetadataChange>,
+    /// Inode of the entry, only set when extended data is enabled on the watcher,
+    /// see [`set_extended_data`](crate::FsEventWatcher::set_extended_data).
+    pub inode: Option<u64>,
+    /// Number of events for [`path`](Event::path) collapsed into this one by sampling,
+    /// see [`set_sampling`](crate::FsEventWatcher::set_sampling), or merged into it by
+    /// [`set_coalescing_window`](crate::FsEventWatcher::set_coalescing_window),
+    /// zero for regular events.
+    pub collapsed: u64,
+    /// Id of the FSEvents callback invocation which delivered the event,
+    /// only set when batch ids are enabled on the watcher,
+    /// see [`set_batch_ids`](crate::FsEventWatcher::set_batch_ids).
+    pub batch: Option<u64>,
+}
+
+/// Kind of a file content modification.
+///
+/// FSEvents does not tell this, it is inferred by comparing file sizes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DataChange {
+    /// Previous size of the file is unknown.
+    Any,
+    /// File size changed, e.g. data was appended or the file was truncated.
+    Size,
+    /// File size is the same, so the content was overwritten in place.
+    Content,
+}
+
+/// Kind of a change of file permissions or ownership.
+///
+/// FSEvents reports both `chmod` and `chown` as `ITEM_CHANGE_OWNER`,
+/// so this is inferred by comparing the mode and the owner with the last known ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MetadataChange {
+    /// Previous mode and owner of the file are unknown.
+    Any,
+    /// Permission bits changed.
+    Mode,
+    /// Owner or group changed.
+    Owner,
+    /// Both permission bits and owner or group changed.
+    ModeAndOwner,
+    /// Neither changed, e.g. only timestamps were modified.
+    Other,
+}
+
+/// Why FSEvents asked for a rescan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RescanReason {
+    /// Events were dropped in the client process, e.g. the handler was too slow.
+    UserDropped,
+    /// Events were dropped in the kernel.
+    KernelDropped,
+    /// Event ids wrapped around, previously stored ids are meaningless.
+    IdsWrapped,
+    /// FSEvents coalesced changes in the subtree without giving a reason.
+    None,
+}
+
+/// Type of the filesystem entry an event is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileClass {
+    File,
+    Dir,
+    Symlink,
+    /// File with more than one hard link.
+    Hardlink,
+    /// Last remaining hard link to a file.
+    LastHardlink,
+}
+
+/// Transition of the FSEvents stream of a watcher,
+/// see [`on_lifecycle`](crate::FsEventWatcher::on_lifecycle).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LifecycleEvent {
+    /// Stream was created, and is about to be started.
+    Created,
+    /// Stream was started, events are delivered.
+    Started,
+    /// Stream could not be started, even after retries.
+    StartFailed,
+    /// Stream was stopped and invalidated, e.g. to be recreated with new settings.
+    Stopped,
+}
+
+/// How event paths which are not valid UTF-8 are decoded,
+/// see [`set_path_decode`](crate::FsEventWatcher::set_path_decode).
+///
+/// macOS filesystems normally only allow UTF-8 names, but e.g. network or FAT volumes may not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PathDecode {
+    /// Drop events with such paths (logging a warning).
+    Strict,
+    /// Replace invalid sequences with `U+FFFD`, so paths can be converted to `String`.
+    Lossy,
+    /// Keep the bytes of the path as is.
+    Raw,
+}
+
+/// Subtree which must be rescanned by the consumer.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RescanInfo {
+    pub reason: RescanReason,
+    pub path: PathBuf,
+}
+
+impl Event {
+    /// FSEvents lost track of changes under [`path`](Event::path),
+    /// so that directory (and only it) must be rescanned by the consumer.
+    pub fn need_rescan(&self) -> bool {
+        self.rescan().is_some()
+    }
+
+    /// Same as [`need_rescan`](Event::need_rescan), but also tells why.
+    pub fn rescan(&self) -> Option<RescanInfo> {
+        let reason = if self.flags.contains(StreamFlags::USER_DROPPED) {
+            RescanReason::UserDropped
+        } else if self.flags.contains(StreamFlags::KERNEL_DROPPED) {
+            RescanReason::KernelDropped
+        } else if self.flags.contains(StreamFlags::IDS_WRAPPED) {
+            RescanReason::IdsWrapped
+        } else if self.flags.contains(StreamFlags::MUST_SCAN_SUBDIRS) {
+            RescanReason::None
+        } else {
+            return None;
+        };
+        Some(RescanInfo {
+            reason,
+            path: self.path.clone(),
+        })
+    }
+
+    /// Paths the event is about, for code handling events of several notification APIs
+    /// uniformly. FSEvents reports a single path per event, including each side of a rename
+    /// (which can be paired by [`inode`](Event::inode)), so this is always one path.
+    pub fn paths(&self) -> &[PathBuf] {
+        slice::from_ref(&self.path)
+    }
+
+    // Note that FSEvents coalesces changes to the same path,
+    // so several of the following predicates can be true for a single event.
+
+    pub fn is_create(&self) -> bool {
+        self.flags.contains(StreamFlags::ITEM_CREATED)
+    }
+
+    pub fn is_remove(&self) -> bool {
+        self.flags.contains(StreamFlags::ITEM_REMOVED)
+    }
+
+    /// Content of the file was modified.
+    pub fn is_modify(&self) -> bool {
+        self.flags.contains(StreamFlags::ITEM_MODIFIED)
+    }
+
+    /// Inode metadata, Finder info, owner or extended attributes were modified.
+    ///
+    /// FSEvents does not tell permission changes from owner changes, see
+    /// [`metadata_change`](Event::metadata_change).
+    pub fn is_metadata_change(&self) -> bool {
+        self.flags.intersects(
+            StreamFlags::INODE_META_MOD
+                | StreamFlags::FINDER_INFO_MOD
+                | StreamFlags::ITEM_CHANGE_OWNER
+                | StreamFlags::ITEM_XATTR_MOD,
+        )
+    }
+
+    /// Short upper case name of the most significant kind of change,
+    /// e.g. `CREATE` or `RESCAN`, as printed by [`Display`](fmt::Display).
+    pub fn kind_name(&self) -> &'static str {
+        if self.is_history_done() {
+            "HISTORY"
+        } else if self.need_rescan() {
+            "RESCAN"
+        } else if self.is_root_changed() {
+            "ROOT"
+        } else if self.is_remove() {
+            "REMOVE"
+        } else if self.is_create() {
+            "CREATE"
+        } else if self.is_rename() {
+            "RENAME"
+        } else if self.is_modify() {
+            "MODIFY"
+        } else if self.is_metadata_change() {
+            "META"
+        } else {
+            "OTHER"
+        }
+    }
+
+    /// Path was renamed, either from or to [`path`](Event::path):
+    /// FSEvents reports both sides of a rename as separate events with the same flag.
+    pub fn is_rename(&self) -> bool {
+        self.flags.contains(StreamFlags::ITEM_RENAMED)
+    }
+
+    /// A volume was mounted under [`path`](Event::path), which is the mount point.
+    pub fn is_mount(&self) -> bool {
+        self.flags.contains(StreamFlags::MOUNT)
+    }
+
+    /// A volume was unmounted from [`path`](Event::path), which is the mount point.
+    pub fn is_unmount(&self) -> bool {
+        self.flags.contains(StreamFlags::UNMOUNT)
+    }
+
+    /// Replay of historical events requested with
+    /// [`watch_since`](crate::FsEventWatcher::watch_since) is finished,
+    /// following events are live. [`path`](Event::path) is meaningless for this event.
+    pub fn is_history_done(&self) -> bool {
+        self.flags.contains(StreamFlags::HISTORY_DONE)
+    }
+
+    /// Watched root was moved, deleted or replaced (reported with the path of the root).
+    pub fn is_root_changed(&self) -> bool {
+        self.flags.contains(StreamFlags::ROOT_CHANGED)
+    }
+
+    /// Watched root was moved or deleted.
+    ///
+    /// No events are delivered for that root until something is created at its path again.
+    /// Note this checks whether [`path`](Event::path) exists at the time of the call.
+    pub fn is_root_removed(&self) -> bool {
+        self.is_root_changed() && !self.path.exists()
+    }
+
+    /// Type of the entry, `None` if FSEvents did not tell
+    /// (e.g. for rescan events or when file events are disabled).
+    pub fn file_type(&self) -> Option<FileClass> {
+        if self.flags.contains(StreamFlags::IS_SYMLINK) {
+            Some(FileClass::Symlink)
+        } else if self.flags.contains(StreamFlags::IS_LAST_HARDLINK) {
+            Some(FileClass::LastHardlink)
+        } else if self.flags.contains(StreamFlags::IS_HARDLINK) {
+            Some(FileClass::Hardlink)
+        } else if self.flags.contains(StreamFlags::IS_DIR) {
+            Some(FileClass::Dir)
+        } else if self.flags.contains(StreamFlags::IS_FILE) {
+            Some(FileClass::File)
+        } else {
+            None
+        }
+    }
+
+    /// Flags reported by FSEvents which this crate does not know about,
+    /// e.g. added in a newer macOS, or zero.
+    pub fn unknown_flags(&self) -> u32 {
+        self.raw_flags & !StreamFlags::all().bits()
+    }
+
+    /// Id of this process if the change was made by it, `None` otherwise.
+    ///
+    /// FSEvents does not tell which process made a change, only whether it was the watching
+    /// process, and only when [`set_mark_self`](crate::FsEventWatcher::set_mark_self) is enabled.
+    pub fn process_id(&self) -> Option<u32> {
+        if self.flags.contains(StreamFlags::OWN_EVENT) {
+            Some(process::id())
+        } else {
+            None
+        }
+    }
+
+    /// Whether the entry is a directory, `None` if FSEvents did not tell.
+    ///
+    /// Unlike `fs::metadata`, this works for removed entries.
+    pub fn is_dir(&self) -> Option<bool> {
+        if self.flags.contains(StreamFlags::IS_DIR) {
+            Some(true)
+        } else if self
+            .flags
+            .intersects(StreamFlags::IS_FILE | StreamFlags::IS_SYMLINK)
+        {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the entry is a regular file, `None` if FSEvents did not tell.
+    ///
+    /// Unlike `fs::metadata`, this works for removed entries.
+    pub fn is_file(&self) -> Option<bool> {
+        if self.flags.contains(StreamFlags::IS_FILE) {
+            Some(true)
+        } else if self
+            .flags
+            .intersects(StreamFlags::IS_DIR | StreamFlags::IS_SYMLINK)
+        {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// The last hard link to a file was removed, so the file data is actually gone,
+    /// as opposed to removal of one of several links, which only changes the link count.
+    pub fn is_last_hardlink_removed(&self) -> bool {
+        self.flags
+            .contains(StreamFlags::ITEM_REMOVED | StreamFlags::IS_LAST_HARDLINK)
+    }
+}
+
+/// Concise one-line summary like `MODIFY(content) /path/to/file [id=123]`.
+///
+/// Only the most significant kind of change is shown, use `{:?}` for all details.
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = self.kind_name();
+        write!(f, "{}", kind)?;
+        match self.data_change {
+            Some(DataChange::Size) if kind == "MODIFY" => write!(f, "(size)")?,
+            Some(DataChange::Content) if kind == "MODIFY" => write!(f, "(content)")?,
+            _ => {}
+        }
+        write!(f, " {} [id={}]", self.path.display(), self.id)
+    }
+}
+
+/// Receives events from the FSEvents callback.
+pub trait EventHandler: Send + 'static {
+    fn handle_event(&mut self, event: Event);
+}
+
+impl<F> EventHandler for F
+where
+    F: FnMut(Event) + Send + 'static,
+{
+    fn handle_event(&mut self, event: Event) {
+        (self)(event);
+    }
+}
+
+/// Forwards events to a channel, so that a consumer can block on the next event
+/// with [`recv_timeout`](crossbeam_channel::Receiver::recv_timeout) instead of sleeping.
+impl EventHandler for Sender<Event> {
+    fn handle_event(&mut self, event: Event) {
+        // Receiver is gone, nobody is interested in events anymore.
+        let _ = self.send(event);
+    }
+}
+
+/// Forwards events to a standard library channel.
+impl EventHandler for mpsc::Sender<Event> {
+    fn handle_event(&mut self, event: Event) {
+        // Receiver is gone, nobody is interested in events anymore.
+        let _ = self.send(event);
+    }
+}
+
+/// Collects events, e.g. for tests to inspect them after the fact.
+impl EventHandler for Arc<Mutex<Vec<Event>>> {
+    fn handle_event(&mut self, event: Event) {
+        self.lock().expect("lock not to be poisoned").push(event);
+    }
+}
+
+/// Forwards events to an async channel, whose receiver is a
+/// [`Stream`](https://docs.rs/futures/latest/futures/stream/trait.Stream.html) of events.
+///
+/// The channel does not depend on a particular runtime, so the receiver
+/// can be polled from tokio, async-std or `futures::executor::block_on`.
+#[cfg(feature = "futures")]
+impl EventHandler for futures_channel::mpsc::UnboundedSender<Event> {
+    fn handle_event(&mut self, event: Event) {
+        // Receiver is gone, nobody is interested in events anymore.
+        let _ = self.unbounded_send(event);
+    }
+}
+
+/// Forwards events to a bounded channel without blocking the FSEvents callback.
+///
+/// When the channel is full, events are dropped. Once there is room again, a single event
+/// with `MUST_SCAN_SUBDIRS | USER_DROPPED` flags for the common ancestor of the dropped
+/// paths is sent before the next event, like FSEvents itself does when a client is too slow.
+pub struct OverflowSender {
+    sender: Sender<Event>,
+    /// Rescan event to send once the channel has room.
+    overflow: Option<Event>,
+}
+
+impl OverflowSender {
+    pub fn new(sender: Sender<Event>) -> OverflowSender {
+        OverflowSender {
+            sender,
+            overflow: None,
+        }
+    }
+
+    fn record_overflow(&mut self, dropped: Event) {
+        let path = match self.overflow.take() {
+            Some(overflow) => common_ancestor(&overflow.path, &dropped.path),
+            None => dropped.path,
+        };
+        let flags = StreamFlags::MUST_SCAN_SUBDIRS | StreamFlags::USER_DROPPED;
+        self.overflow = Some(Event {
+            path,
+            flags,
+            raw_flags: flags.bits(),
+            id: dropped.id,
+            data_change: None,
+            metadata_change: None,
+            inode: None,
+            collapsed: 0,
+            batch: None,
+        });
+    }
+}
+
+impl EventHandler for OverflowSender {
+    fn handle_event(&mut self, event: Event) {
+        if let Some(overflow) = self.overflow.take() {
+            match self.sender.try_send(overflow) {
+                Ok(()) => {}
+                Err(TrySendError::Full(overflow)) => {
+                    self.overflow = Some(overflow);
+                    self.record_overflow(event);
+                    return;
+                }
+                Err(TrySendError::Disconnected(_)) => return,
+            }
+        }
+        match self.sender.try_send(event) {
+            Ok(()) | Err(TrySendError::Disconnected(_)) => {}
+            Err(TrySendError::Full(event)) => self.record_overflow(event),
+        }
+    }
+}
+
+/// What [`BoundedSender`] does with an event when the channel is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DropPolicy {
+    /// Wait for the consumer to make room.
+    ///
+    /// No events are lost, but the FSEvents callback is stalled meanwhile, and so is
+    /// the run loop with all other streams scheduled on it. FSEvents keeps queueing events,
+    /// and if the kernel queue overflows, it drops them and requests a rescan anyway.
+    Block,
+    /// Drop the event being delivered, keeping older events in the channel.
+    DropNewest,
+    /// Drop the oldest event in the channel to make room for the new one.
+    DropOldest,
+}
+
+/// Forwards events to a bounded channel, handling a full channel according to a [`DropPolicy`].
+///
+/// Unlike [`OverflowSender`], dropped events are not reported to the consumer.
+pub struct BoundedSender {
+    sender: Sender<Event>,
+    /// To drop the oldest event, only for [`DropPolicy::DropOldest`], because it keeps
+    /// the channel connected after the consumer drops its receiver.
+    receiver: Option<Receiver<Event>>,
+    policy: DropPolicy,
+}
+
+impl BoundedSender {
+    /// Create a channel with room for `capacity` events, returning the handler
+    /// to pass to the watcher and the receiving side.
+    ///
+    /// With zero `capacity` events are handed over only to a consumer already waiting
+    /// in `recv`, and [`DropPolicy::DropOldest`] drops the new event like
+    /// [`DropPolicy::DropNewest`], as there are no older events to make room.
+    pub fn new(capacity: usize, policy: DropPolicy) -> (BoundedSender, Receiver<Event>) {
+        let policy = match policy {
+            DropPolicy::DropOldest if capacity == 0 => DropPolicy::DropNewest,
+            policy => policy,
+        };
+        let (sender, receiver) = crossbeam_channel::bounded(capacity);
+        let bounded_sender = BoundedSender {
+            sender,
+            receiver: match policy {
+                DropPolicy::DropOldest => Some(receiver.clone()),
+                DropPolicy::Block | DropPolicy::DropNewest => None,
+            },
+            policy,
+        };
+        (bounded_sender, receiver)
+    }
+}
+
+impl EventHandler for BoundedSender {
+    fn handle_event(&mut self, mut event: Event) {
+        // Errors mean the receiver is gone, nobody is interested in events anymore.
+        match self.policy {
+            DropPolicy::Block => {
+                let _ = self.sender.send(event);
+            }
+            DropPolicy::DropNewest => {
+                let _ = self.sender.try_send(event);
+            }
+            DropPolicy::DropOldest => loop {
+                match self.sender.try_send(event) {
+                    Ok(()) | Err(TrySendError::Disconnected(_)) => return,
+                    Err(TrySendError::Full(rejected)) => {
+                        // The consumer may have taken it already, then there is room now.
+                        if let Some(receiver) = &self.receiver {
+                            let _ = receiver.try_recv();
+                        }
+                        event = rejected;
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Handler added with [`add_event_handler`](crate::FsEventWatcher::add_event_handler),
+/// on top of the handlers added before it.
+pub(crate) struct Fanout {
+    pub(crate) previous: Box<dyn EventHandler>,
+    pub(crate) filter: Box<dyn Fn(&Event) -> bool + Send>,
+    pub(crate) handler: Box<dyn EventHandler>,
+}
+
+impl EventHandler for Fanout {
+    fn handle_event(&mut self, event: Event) {
+        if (self.filter)(&event) {
+            self.previous.handle_event(event.clone());
+            self.handler.handle_event(event);
+        } else {
+            self.previous.handle_event(event);
+        }
+    }
+}
+
+pub(crate) fn common_ancestor(a: &Path, b: &Path) -> PathBuf {
+    a.components()
+        .zip(b.components())
+        .take_while(|(a, b)| a == b)
+        .map(|(a, _)| a)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn event(path: &str, flags: StreamFlags, id: u64) -> Event {
+        Event {
+            path: PathBuf::from(path),
+            flags,
+            raw_flags: flags.bits(),
+            id,
+            data_change: None,
+            metadata_change: None,
+            inode: None,
+            collapsed: 0,
+            batch: None,
+        }
+    }
+
+    #[test]
+    fn eq_and_hash() {
+        let create = event("/a", StreamFlags::ITEM_CREATED, 1);
+        let mut events = HashSet::new();
+        assert!(events.insert(create.clone()));
+        assert!(!events.insert(create.clone()));
+        // Same change reported again.
+        assert!(events.insert(event("/a", StreamFlags::ITEM_CREATED, 2)));
+        assert!(events.insert(Event {
+            data_change: Some(DataChange::Size),
+            ..create
+        }));
+        assert_eq!(events.len(), 3);
+    }
+
+    #[test]
+    fn predicates() {
+        let created = event("/a", StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE, 1);
+        assert!(created.is_create());
+        assert!(!created.is_remove());
+        assert!(!created.is_modify());
+        assert!(!created.is_rename());
+        assert!(!created.need_rescan());
+
+        // FSEvents coalesces flags, so several predicates can hold.
+        let coalesced = event(
+            "/a",
+            StreamFlags::ITEM_CREATED | StreamFlags::ITEM_MODIFIED | StreamFlags::ITEM_REMOVED,
+            2,
+        );
+        assert!(coalesced.is_create() && coalesced.is_modify() && coalesced.is_remove());
+
+        let renamed = event("/a", StreamFlags::ITEM_RENAMED, 3);
+        assert!(renamed.is_rename());
+
+        let rescan = event(
+            "/a",
+            StreamFlags::MUST_SCAN_SUBDIRS | StreamFlags::KERNEL_DROPPED,
+            4,
+        );
+        assert!(rescan.need_rescan());
+        assert!(matches!(
+            rescan.rescan().unwrap().reason,
+            RescanReason::KernelDropped
+        ));
+
+        let chmod = event("/a", StreamFlags::INODE_META_MOD | StreamFlags::IS_FILE, 5);
+        assert!(chmod.is_metadata_change());
+        assert!(!chmod.is_modify());
+    }
+
+    #[test]
+    fn is_dir_and_is_file() {
+        let file = event("/a", StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE, 1);
+        assert_eq!(file.is_file(), Some(true));
+        assert_eq!(file.is_dir(), Some(false));
+        assert_eq!(file.file_type(), Some(FileClass::File));
+
+        let dir = event("/a", StreamFlags::ITEM_REMOVED | StreamFlags::IS_DIR, 2);
+        assert_eq!(dir.is_file(), Some(false));
+        assert_eq!(dir.is_dir(), Some(true));
+
+        let symlink = event("/a", StreamFlags::ITEM_RENAMED | StreamFlags::IS_SYMLINK, 3);
+        assert_eq!(symlink.is_file(), Some(false));
+        assert_eq!(symlink.is_dir(), Some(false));
+
+        let rescan = event("/a", StreamFlags::MUST_SCAN_SUBDIRS, 4);
+        assert_eq!(rescan.is_file(), None);
+        assert_eq!(rescan.is_dir(), None);
+        assert_eq!(rescan.file_type(), None);
+    }
+
+    #[test]
+    fn unknown_flags() {
+        let created = event("/a", StreamFlags::ITEM_CREATED, 1);
+        assert_eq!(created.unknown_flags(), 0);
+        let unknown = Event {
+            raw_flags: 0x1000_0000 | StreamFlags::ITEM_CREATED.bits(),
+            ..created
+        };
+        assert!(unknown.is_create());
+        assert_eq!(unknown.unknown_flags(), 0x1000_0000);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            event("/a/b", StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE, 3).to_string(),
+            "CREATE /a/b [id=3]"
+        );
+        // Most significant kind wins.
+        assert_eq!(
+            event(
+                "/a",
+                StreamFlags::ITEM_CREATED | StreamFlags::ITEM_REMOVED,
+                4
+            )
+            .to_string(),
+            "REMOVE /a [id=4]"
+        );
+        let modified = Event {
+            data_change: Some(DataChange::Content),
+            ..event("/a", StreamFlags::ITEM_MODIFIED, 5)
+        };
+        assert_eq!(modified.to_string(), "MODIFY(content) /a [id=5]");
+        assert_eq!(modified.kind_name(), "MODIFY");
+        assert_eq!(event("/a", StreamFlags::empty(), 6).kind_name(), "OTHER");
+    }
+
+    #[test]
+    fn overflow_sender_reports_dropped_events() {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let mut sender = OverflowSender::new(tx);
+        sender.handle_event(event("/a/b/1", StreamFlags::ITEM_CREATED, 1));
+        sender.handle_event(event("/a/b/2", StreamFlags::ITEM_CREATED, 2));
+        sender.handle_event(event("/a/c/3", StreamFlags::ITEM_CREATED, 3));
+        assert_eq!(rx.try_recv().unwrap().id, 1);
+        assert!(rx.try_recv().is_err());
+
+        // Rescan of the dropped paths goes first, the new event is dropped again.
+        sender.handle_event(event("/a/b/4", StreamFlags::ITEM_CREATED, 4));
+        let rescan = rx.try_recv().unwrap();
+        assert_eq!(rescan.path, PathBuf::from("/a"));
+        assert!(matches!(
+            rescan.rescan().unwrap().reason,
+            RescanReason::UserDropped
+        ));
+        assert!(rx.try_recv().is_err());
+
+        sender.handle_event(event("/a/b/5", StreamFlags::ITEM_CREATED, 5));
+        let rescan = rx.try_recv().unwrap();
+        assert_eq!(rescan.path, PathBuf::from("/a/b/4"));
+        assert!(rescan.need_rescan());
+    }
+
+    #[test]
+    fn bounded_sender_policies() {
+        let (mut sender, rx) = BoundedSender::new(1, DropPolicy::DropNewest);
+        sender.handle_event(event("/a", StreamFlags::ITEM_CREATED, 1));
+        sender.handle_event(event("/a", StreamFlags::ITEM_CREATED, 2));
+        assert_eq!(rx.try_iter().map(|event| event.id).collect::<Vec<_>>(), [1]);
+
+        let (mut sender, rx) = BoundedSender::new(1, DropPolicy::DropOldest);
+        sender.handle_event(event("/a", StreamFlags::ITEM_CREATED, 1));
+        sender.handle_event(event("/a", StreamFlags::ITEM_CREATED, 2));
+        assert_eq!(rx.try_iter().map(|event| event.id).collect::<Vec<_>>(), [2]);
+
+        let (mut sender, rx) = BoundedSender::new(1, DropPolicy::Block);
+        sender.handle_event(event("/a", StreamFlags::ITEM_CREATED, 1));
+        assert_eq!(rx.try_recv().unwrap().id, 1);
+        // Does not block once the receiver is gone.
+        drop(rx);
+        sender.handle_event(event("/a", StreamFlags::ITEM_CREATED, 2));
+        sender.handle_event(event("/a", StreamFlags::ITEM_CREATED, 3));
+    }
+
+    #[test]
+    fn bounded_sender_without_capacity() {
+        // Returns instead of waiting for room which never appears.
+        let (mut sender, rx) = BoundedSender::new(0, DropPolicy::DropOldest);
+        sender.handle_event(event("/a", StreamFlags::ITEM_CREATED, 1));
+        assert!(rx.try_recv().is_err());
+    }
+}
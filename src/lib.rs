@@ -1,16 +1,93 @@
 #![allow(non_upper_case_globals)]
 
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::env;
 use std::ffi::CStr;
+use std::ffi::CString;
+use std::ffi::OsStr;
+use std::fs as std_fs;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::RawFd;
+use std::panic;
+use std::panic::AssertUnwindSafe;
 use std::path::Path;
 use std::path::PathBuf;
+use std::process;
 use std::ptr;
+use std::str;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
 use fsevent_sys as fs;
 use fsevent_sys::core_foundation as cf;
+use globset::GlobBuilder;
+use globset::GlobSet;
+use globset::GlobSetBuilder;
+use log::debug;
+use log::trace;
+use log::warn;
+use walkdir::WalkDir;
+
+mod coalesce;
+mod config;
+mod content;
+mod error;
+mod event;
+#[cfg(feature = "testing")]
+mod mock;
+mod multi;
+mod roots;
+mod runloop;
+mod sampling;
+mod stats;
+
+use crate::coalesce::Coalescer;
+pub use crate::config::WatcherConfig;
+use crate::content::ContentHashes;
+pub use crate::error::Error;
+pub use crate::error::ErrorKind;
+pub use crate::error::Result;
+pub use crate::event::BoundedSender;
+pub use crate::event::DataChange;
+pub use crate::event::DropPolicy;
+pub use crate::event::Event;
+pub use crate::event::EventHandler;
+use crate::event::Fanout;
+pub use crate::event::FileClass;
+pub use crate::event::LifecycleEvent;
+pub use crate::event::MetadataChange;
+pub use crate::event::OverflowSender;
+pub use crate::event::PathDecode;
+pub use crate::event::RescanInfo;
+pub use crate::event::RescanReason;
+#[cfg(feature = "testing")]
+pub use crate::mock::MockWatcher;
+pub use crate::multi::MultiWatcher;
+use crate::roots::RootTrie;
+use crate::runloop::CFRunLoopTimerRef;
+pub use crate::runloop::RunLoopThread;
+use crate::sampling::Sampler;
+use crate::stats::Counters;
+pub use crate::stats::WatcherStats;
 
 bitflags::bitflags! {
+  /// Flags of an FSEvents event.
+  ///
+  /// FSEvents only reports modifications: none of the flags means a read or an open,
+  /// so file access cannot be observed through FSEvents. `INODE_META_MOD` is reported
+  /// for metadata changes including times, but not for `atime` updates caused by reads.
   #[repr(C)]
-  struct StreamFlags: u32 {
+  pub struct StreamFlags: u32 {
     const NONE = fs::kFSEventStreamEventFlagNone;
     const MUST_SCAN_SUBDIRS = fs::kFSEventStreamEventFlagMustScanSubDirs;
     const USER_DROPPED = fs::kFSEventStreamEventFlagUserDropped;
@@ -38,70 +115,2006 @@ bitflags::bitflags! {
   }
 }
 
+impl StreamFlags {
+    /// Flags of events which are delivered regardless of
+    /// [`set_interest`](FsEventWatcher::set_interest).
+    pub const ALWAYS_DELIVERED: StreamFlags = StreamFlags::from_bits_truncate(
+        StreamFlags::MUST_SCAN_SUBDIRS.bits()
+            | StreamFlags::USER_DROPPED.bits()
+            | StreamFlags::KERNEL_DROPPED.bits()
+            | StreamFlags::IDS_WRAPPED.bits()
+            | StreamFlags::HISTORY_DONE.bits()
+            | StreamFlags::ROOT_CHANGED.bits()
+            | StreamFlags::MOUNT.bits()
+            | StreamFlags::UNMOUNT.bits(),
+    );
+
+    /// Names of the set flags, e.g. `["ITEM_CREATED", "IS_FILE"]`.
+    pub fn names(self) -> Vec<&'static str> {
+        const NAMES: &[(StreamFlags, &str)] = &[
+            (StreamFlags::MUST_SCAN_SUBDIRS, "MUST_SCAN_SUBDIRS"),
+            (StreamFlags::USER_DROPPED, "USER_DROPPED"),
+            (StreamFlags::KERNEL_DROPPED, "KERNEL_DROPPED"),
+            (StreamFlags::IDS_WRAPPED, "IDS_WRAPPED"),
+            (StreamFlags::HISTORY_DONE, "HISTORY_DONE"),
+            (StreamFlags::ROOT_CHANGED, "ROOT_CHANGED"),
+            (StreamFlags::MOUNT, "MOUNT"),
+            (StreamFlags::UNMOUNT, "UNMOUNT"),
+            (StreamFlags::ITEM_CREATED, "ITEM_CREATED"),
+            (StreamFlags::ITEM_REMOVED, "ITEM_REMOVED"),
+            (StreamFlags::INODE_META_MOD, "INODE_META_MOD"),
+            (StreamFlags::ITEM_RENAMED, "ITEM_RENAMED"),
+            (StreamFlags::ITEM_MODIFIED, "ITEM_MODIFIED"),
+            (StreamFlags::FINDER_INFO_MOD, "FINDER_INFO_MOD"),
+            (StreamFlags::ITEM_CHANGE_OWNER, "ITEM_CHANGE_OWNER"),
+            (StreamFlags::ITEM_XATTR_MOD, "ITEM_XATTR_MOD"),
+            (StreamFlags::IS_FILE, "IS_FILE"),
+            (StreamFlags::IS_DIR, "IS_DIR"),
+            (StreamFlags::IS_SYMLINK, "IS_SYMLINK"),
+            (StreamFlags::OWN_EVENT, "OWN_EVENT"),
+            (StreamFlags::IS_HARDLINK, "IS_HARDLINK"),
+            (StreamFlags::IS_LAST_HARDLINK, "IS_LAST_HARDLINK"),
+            (StreamFlags::ITEM_CLONED, "ITEM_CLONED"),
+        ];
+        NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
 pub struct FsEventWatcher {
     paths: cf::CFMutableArrayRef,
     since_when: fs::FSEventStreamEventId,
+    /// Seconds, see [`set_latency`](FsEventWatcher::set_latency).
     latency: cf::CFTimeInterval,
     flags: fs::FSEventStreamCreateFlags,
+    event_handler: Arc<Mutex<Box<dyn EventHandler>>>,
+    /// Run loop of the watcher thread, the stream running on it, and the thread.
+    runloop: Option<(
+        cf::CFRunLoopRef,
+        fs::FSEventStreamRef,
+        thread::JoinHandle<()>,
+    )>,
+    external_runloop: bool,
+    shared_runloop: Option<Arc<RunLoopThread>>,
+    /// Stream, owned run loop mode and the run loop,
+    /// when scheduled on an external or a shared run loop.
+    external_stream: Option<(fs::FSEventStreamRef, cf::CFStringRef, cf::CFRunLoopRef)>,
+    last_event_id: Arc<AtomicU64>,
+    /// Set by the callback when a volume containing a root was mounted again.
+    remounted: Arc<AtomicBool>,
+    replay_paused_events: bool,
+    max_replay_events: Option<u64>,
+    deliver_history_done: bool,
+    /// Next batch id, if batch ids are enabled.
+    batch_ids: Option<Arc<AtomicU64>>,
+    paused: bool,
+    roots: Vec<WatchRoot>,
+    include_paths: Vec<PathBuf>,
+    ignore_globs_builder: GlobSetBuilder,
+    /// Patterns added to `ignore_globs_builder`, for [`to_config`](FsEventWatcher::to_config).
+    ignore_patterns: Vec<String>,
+    /// Shared with the callback, so changes take effect without restarting the stream.
+    ignore_globs: Arc<Mutex<GlobSet>>,
+    run_loop_mode: Option<String>,
+    label: Option<CString>,
+    suppress_ephemeral: bool,
+    ignore_hidden: bool,
+    coalesce_renames: bool,
+    path_decode: PathDecode,
+    interest: Option<StreamFlags>,
+    follow_symlinks: bool,
+    max_depth: Option<usize>,
+    id_gap_threshold: Option<u64>,
+    sampling: Option<(usize, Duration)>,
+    coalescing_window: Option<Duration>,
+    consolidate_roots: Option<usize>,
+    /// Hashes of file contents, if content hashing is enabled.
+    content_hashes: Option<Arc<ContentHashes>>,
+    watch_missing_paths: bool,
+    /// Last known sizes of files, if size tracking is enabled.
+    file_sizes: Option<Arc<Mutex<HashMap<PathBuf, u64>>>>,
+    /// Last known modes and owners of files, if permission tracking is enabled.
+    file_modes: Option<Arc<Mutex<HashMap<PathBuf, FileMode>>>>,
+    start_retries: u32,
+    start_retry_delay: Duration,
+    stop_timeout: Option<Duration>,
+    counters: Arc<Counters>,
+    lifecycle_handler: Option<Box<dyn Fn(LifecycleEvent) + Send>>,
+    panic_handler: Option<Arc<dyn Fn(String) + Send + Sync>>,
+}
+
+// Raw CF pointers are what prevents the auto traits, and they are used as follows:
+//
+// * `paths` is copied by `FSEventStreamCreate`, so the callback never reads it.
+//   It is only mutated with `&mut self` while the stream is stopped (asserted),
+//   and read with `&self`, which is safe to do concurrently.
+// * The run loop ref of the watcher thread is only used with thread-safe calls,
+//   `CFRunLoopIsWaiting` and `CFRunLoopStop`.
+// * The stream is only stopped and released by `stop` with `&mut self`, on the thread
+//   running its loop, between callbacks: after the watcher thread was joined,
+//   on the shared thread (`RunLoopThread::perform_and_wait`), or on the thread which
+//   scheduled it on its current run loop. The watcher can still be moved to another
+//   thread in the last case, so `stop` asserts it is called on the scheduling thread,
+//   as documented on `schedule_on_current_runloop`.
+//   `&self` only uses it for `FSEventStreamCopyPathsBeingWatched` and
+//   `FSEventStreamFlushAsync`, which do not change the stream configuration.
+// * Everything shared with the callback thread is behind `Arc`, `Mutex` or atomics
+//   in `StreamContextInfo`.
+//
+// `lifecycle_handler` is not `Sync`, but it is only called from `&mut self` methods.
+unsafe impl Send for FsEventWatcher {}
+unsafe impl Sync for FsEventWatcher {}
+
+struct StreamContextInfo {
+    event_handler: Arc<Mutex<Box<dyn EventHandler>>>,
+    last_event_id: Arc<AtomicU64>,
+    roots: Vec<WatchRoot>,
+    /// Include paths, and whether they are on a case-insensitive volume.
+    include_paths: Vec<(PathBuf, bool)>,
+    ignore_globs: Arc<Mutex<GlobSet>>,
+    counters: Arc<Counters>,
+    file_sizes: Option<Arc<Mutex<HashMap<PathBuf, u64>>>>,
+    file_modes: Option<Arc<Mutex<HashMap<PathBuf, FileMode>>>>,
+    label: Option<CString>,
+    suppress_ephemeral: bool,
+    ignore_hidden: bool,
+    follow_symlinks: bool,
+    max_depth: Option<usize>,
+    extended_data: bool,
+    id_gap_threshold: Option<u64>,
+    sampler: Option<Sampler>,
+    coalescer: Option<Coalescer>,
+    content_hashes: Option<Arc<ContentHashes>>,
+    coalesce_renames: bool,
+    path_decode: PathDecode,
+    interest: Option<StreamFlags>,
+    root_trie: RootTrie,
+    /// Unmounted volumes below or containing roots, to detect when they are mounted again.
+    unmounted: Mutex<HashSet<PathBuf>>,
+    remounted: Arc<AtomicBool>,
+    /// `None` if replay is not limited or not replaying.
+    replay_budget: Option<Mutex<ReplayBudget>>,
+    deliver_history_done: bool,
+    batch_ids: Option<Arc<AtomicU64>>,
+    panic_handler: Option<Arc<dyn Fn(String) + Send + Sync>>,
+}
+
+/// State of a replay limited by [`FsEventWatcher::set_max_replay_events`].
+enum ReplayBudget {
+    /// Number of historical events left to deliver.
+    Left(u64),
+    /// The limit was reached, historical events are dropped.
+    Exhausted,
+    /// Replay is finished.
+    Live,
+}
+
+impl StreamContextInfo {
+    /// Whether the consumer is interested in the event, checked before the path is decoded.
+    fn interested_in(&self, flags: StreamFlags) -> bool {
+        let interest = match self.interest {
+            Some(interest) => interest,
+            None => return true,
+        };
+        flags.intersects(interest | StreamFlags::ALWAYS_DELIVERED)
+    }
+
+    /// Pass an event received from FSEvents to the handler.
+    fn deliver(&self, event_handler: &mut dyn EventHandler, event: Event) {
+        self.counters.forwarded();
+        self.invoke(event_handler, event);
+    }
+
+    /// Pass an event generated by the watcher to the handler.
+    fn deliver_synthetic(&self, event_handler: &mut dyn EventHandler, event: Event) {
+        self.counters.synthetic();
+        self.invoke(event_handler, event);
+    }
+
+    /// Call the handler, catching panics, so they do not unwind
+    /// into FSEvents and the stream keeps delivering events.
+    fn invoke(&self, event_handler: &mut dyn EventHandler, event: Event) {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| event_handler.handle_event(event)));
+        if let Err(payload) = result {
+            let message = if let Some(message) = payload.downcast_ref::<&str>() {
+                (*message).to_owned()
+            } else if let Some(message) = payload.downcast_ref::<String>() {
+                message.clone()
+            } else {
+                "unknown panic".to_owned()
+            };
+            warn!("event handler panicked: {}", message);
+            self.counters.handler_panic();
+            if let Some(panic_handler) = &self.panic_handler {
+                panic_handler(message);
+            }
+        }
+    }
+
+    /// Deliver events merged by the coalescer, if any.
+    fn deliver_coalesced(&self, event_handler: &mut dyn EventHandler) {
+        if let Some(coalescer) = &self.coalescer {
+            for event in coalescer.take() {
+                self.deliver(event_handler, event);
+            }
+        }
+    }
+
+    /// Whether the event passes the filters configured on the watcher.
+    fn accepts(&self, path: &Path, flags: StreamFlags) -> bool {
+        if flags.contains(StreamFlags::HISTORY_DONE) {
+            // Path is meaningless for this event, so filters do not apply.
+            return true;
+        }
+        if !self.root_trie.matches(path, flags) {
+            return false;
+        }
+        if let Some(max_depth) = self.max_depth {
+            // Depth relative to the deepest root containing the path; events for
+            // ancestors of roots (e.g. rescans) are not under any root and pass.
+            let depth = self
+                .roots
+                .iter()
+                .filter_map(|root| root.strip_prefix(path))
+                .map(|rest| rest.components().count())
+                .min();
+            if depth.is_some_and(|depth| depth > max_depth) {
+                return false;
+            }
+        }
+        if !self.include_paths.is_empty()
+            && !self
+                .include_paths
+                .iter()
+                .any(|(p, case_insensitive)| is_under(p, path, flags, *case_insensitive))
+        {
+            return false;
+        }
+        let ignore_globs = self.ignore_globs.lock().expect("lock not to be poisoned");
+        if ignore_globs.is_match(path)
+            || self
+                .roots
+                .iter()
+                .filter_map(|root| root.strip_prefix(path))
+                .any(|rest| ignore_globs.is_match(rest))
+        {
+            return false;
+        }
+        if self.ignore_hidden && self.is_hidden_entry(path) {
+            return false;
+        }
+        true
+    }
+
+    /// Whether `path` is a dot file under a root, roots themselves are never hidden.
+    fn is_hidden_entry(&self, path: &Path) -> bool {
+        let hidden = path
+            .file_name()
+            .is_some_and(|name| name.as_bytes().starts_with(b"."));
+        if !hidden {
+            return false;
+        }
+        // With roots `/x` and `/x/.hidden`, the latter is under the former, but still a root.
+        let mut rests = self
+            .roots
+            .iter()
+            .filter_map(|root| root.strip_prefix(path))
+            .peekable();
+        rests.peek().is_some() && rests.all(|rest| !rest.as_os_str().is_empty())
+    }
+
+    /// Paths to rescan if the volume mounted at `path` was unmounted earlier
+    /// and is mounted again now: the mount point if it is below a root,
+    /// or roots on the volume if it is mounted above them.
+    fn remounted_paths(&self, path: &Path, flags: StreamFlags) -> Vec<PathBuf> {
+        if !flags.intersects(StreamFlags::MOUNT | StreamFlags::UNMOUNT) {
+            return Vec::new();
+        }
+        let paths: Vec<_> = if self
+            .roots
+            .iter()
+            .any(|root| root.strip_prefix(path).is_some())
+        {
+            vec![path.to_owned()]
+        } else {
+            self.roots
+                .iter()
+                .filter(|root| {
+                    roots::strip_prefix(&root.path, path, root.case_insensitive).is_some()
+                })
+                .map(|root| root.path.clone())
+                .collect()
+        };
+        if paths.is_empty() {
+            return Vec::new();
+        }
+        let mut unmounted = self.unmounted.lock().expect("lock not to be poisoned");
+        if flags.contains(StreamFlags::UNMOUNT) {
+            unmounted.insert(path.to_owned());
+            return Vec::new();
+        }
+        if !unmounted.remove(path) {
+            return Vec::new();
+        }
+        self.remounted.store(true, Ordering::SeqCst);
+        paths
+    }
+
+    /// Whether a historical event fits into the replay limit.
+    ///
+    /// Returns `None` if it does, or roots to request a rescan of for the first event
+    /// which does not fit (and an empty list for the following events).
+    fn exceeds_replay_budget(&self, flags: StreamFlags) -> Option<&[WatchRoot]> {
+        let mut budget = self
+            .replay_budget
+            .as_ref()?
+            .lock()
+            .expect("lock not to be poisoned");
+        match *budget {
+            ReplayBudget::Live => None,
+            _ if flags.contains(StreamFlags::HISTORY_DONE) => {
+                *budget = ReplayBudget::Live;
+                None
+            }
+            ReplayBudget::Left(0) => {
+                *budget = ReplayBudget::Exhausted;
+                Some(&self.roots)
+            }
+            ReplayBudget::Left(left) => {
+                *budget = ReplayBudget::Left(left - 1);
+                None
+            }
+            ReplayBudget::Exhausted => Some(&[]),
+        }
+    }
+
+    /// Path to report for the event: as is, or under the watched path as it was passed
+    /// to `watch` if symlinks are not followed.
+    fn report_path(&self, path: PathBuf) -> PathBuf {
+        if self.follow_symlinks {
+            return path;
+        }
+        for root in &self.roots {
+            if let Some(rest) = root.strip_prefix(&path) {
+                if rest.as_os_str().is_empty() {
+                    return root.original.clone();
+                }
+                return root.original.join(rest);
+            }
+        }
+        path
+    }
+
+    /// Update the last known size of the file, and tell how a modification changed it.
+    fn track_size(&self, path: &Path, flags: StreamFlags) -> Option<DataChange> {
+        let file_sizes = self.file_sizes.as_ref()?;
+        if flags.contains(StreamFlags::IS_DIR) {
+            return None;
+        }
+        let mut file_sizes = file_sizes.lock().expect("lock not to be poisoned");
+        let size = match std_fs::metadata(path) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => {
+                // Removed, or renamed away.
+                file_sizes.remove(path);
+                return None;
+            }
+        };
+        let previous = file_sizes.insert(path.to_owned(), size);
+        if !flags.contains(StreamFlags::ITEM_MODIFIED) {
+            return None;
+        }
+        Some(match previous {
+            None => DataChange::Any,
+            Some(previous) if previous != size => DataChange::Size,
+            Some(_) => DataChange::Content,
+        })
+    }
+
+    /// Update the last known mode and owner of the file, and tell how
+    /// a metadata change changed them.
+    fn track_mode(&self, path: &Path, flags: StreamFlags) -> Option<MetadataChange> {
+        let file_modes = self.file_modes.as_ref()?;
+        let mut file_modes = file_modes.lock().expect("lock not to be poisoned");
+        let mode = match std_fs::metadata(path) {
+            Ok(metadata) => FileMode {
+                mode: metadata.mode(),
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+            },
+            Err(_) => {
+                file_modes.remove(path);
+                return None;
+            }
+        };
+        let previous = file_modes.insert(path.to_owned(), mode);
+        if !flags.intersects(StreamFlags::ITEM_CHANGE_OWNER | StreamFlags::INODE_META_MOD) {
+            return None;
+        }
+        let previous = match previous {
+            Some(previous) => previous,
+            None => return Some(MetadataChange::Any),
+        };
+        let mode_changed = previous.mode != mode.mode;
+        let owner_changed = (previous.uid, previous.gid) != (mode.uid, mode.gid);
+        Some(match (mode_changed, owner_changed) {
+            (true, true) => MetadataChange::ModeAndOwner,
+            (true, false) => MetadataChange::Mode,
+            (false, true) => MetadataChange::Owner,
+            (false, false) => MetadataChange::Other,
+        })
+    }
+}
+
+/// Last known permissions and owner of a file,
+/// see [`FsEventWatcher::set_track_permission_changes`].
+#[derive(Clone, Copy)]
+struct FileMode {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+}
+
+/// Path requested by the user, used to filter events from the paths passed to FSEvents.
+#[derive(Clone, Debug)]
+struct WatchRoot {
+    /// Canonical path.
+    path: PathBuf,
+    /// Absolute path as passed to `watch`, with symlinks not resolved.
+    original: PathBuf,
+    /// FSEvents cannot watch files, so the parent directory is watched,
+    /// and only events for `path` itself are delivered.
+    is_file: bool,
+    /// The root is on a case-insensitive volume (the default for APFS and HFS+),
+    /// so event paths may differ in case from `path`.
+    case_insensitive: bool,
+}
+
+impl WatchRoot {
+    /// `path` relative to the root, ignoring case on a case-insensitive volume.
+    fn strip_prefix<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        roots::strip_prefix(path, &self.path, self.case_insensitive)
+    }
+}
+
+/// Convert `path` to the form FSEvents uses for event paths: absolute, with symlinks resolved.
+///
+/// On macOS `/tmp`, `/var` and `/etc` are symlinks into `/private`, so a watch
+/// of `/tmp/foo` reports events for `/private/tmp/foo/...`. Event paths should be compared
+/// to the result of this function rather than to the path passed to `watch`.
+///
+/// For a path which does not exist yet, its nearest existing ancestor is resolved.
+pub fn fsevents_path(path: &Path) -> Option<PathBuf> {
+    match path.canonicalize() {
+        Ok(path) => Some(path),
+        Err(_) => nearest_existing_ancestor(path).map(|(_, path)| path),
+    }
+}
+
+/// Deepest common ancestor of `paths`, e.g. `/a` for `/a/b` and `/a/c`,
+/// `None` if `paths` is empty or relative paths have no common prefix.
+///
+/// Watching the ancestor of many sibling paths is often cheaper than watching
+/// them all, see [`FsEventWatcher::set_consolidate_roots`].
+pub fn common_ancestor(paths: &[PathBuf]) -> Option<PathBuf> {
+    let (first, rest) = paths.split_first()?;
+    let ancestor = rest.iter().fold(first.clone(), |ancestor, path| {
+        event::common_ancestor(&ancestor, path)
+    });
+    if ancestor.as_os_str().is_empty() {
+        return None;
+    }
+    Some(ancestor)
+}
+
+/// Id of the device (volume) `path` is on, as `st_dev` of `stat`.
+///
+/// FSEvents can watch paths relative to a device, which keeps working when the volume
+/// is remounted at a different mount point.
+pub fn device_for_path(path: &Path) -> Result<libc::dev_t> {
+    let metadata = std_fs::metadata(path).map_err(|e| Error::from(e).add_path(path.to_owned()))?;
+    Ok(metadata.dev() as libc::dev_t)
+}
+
+/// Whether names on the volume of an existing `path` are case-sensitive,
+/// assumed so if it cannot be determined.
+fn is_case_sensitive(path: &Path) -> bool {
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return true,
+    };
+    unsafe { libc::pathconf(path.as_ptr(), _PC_CASE_SENSITIVE) != 0 }
+}
+
+/// `path` as a string to pass to FSEvents, which takes paths as `CFString`.
+fn path_to_str(path: &Path) -> Result<&str> {
+    path.to_str().ok_or_else(|| {
+        Error::generic("Path is not valid UTF-8".to_owned()).add_path(path.to_owned())
+    })
+}
+
+/// Stream flags restored from a [`WatcherConfig`], only those the watcher can handle.
+fn supported_create_flags(flags: u32) -> Result<fs::FSEventStreamCreateFlags> {
+    let extended_data =
+        fs::kFSEventStreamCreateFlagUseCFTypes | fs::kFSEventStreamCreateFlagUseExtendedData;
+    let supported = fs::kFSEventStreamCreateFlagFileEvents
+        | fs::kFSEventStreamCreateFlagNoDefer
+        | fs::kFSEventStreamCreateFlagWatchRoot
+        | fs::kFSEventStreamCreateFlagMarkSelf
+        | extended_data;
+    if flags & !supported != 0 {
+        return Err(Error::generic(format!(
+            "Unsupported stream flags {:#x}",
+            flags & !supported
+        )));
+    }
+    // The callback reads CF types only in the extended data layout, and C strings otherwise.
+    let flags_extended_data = flags & extended_data;
+    if flags_extended_data != 0 && flags_extended_data != extended_data {
+        return Err(Error::generic(
+            "UseCFTypes and UseExtendedData stream flags must be set together".to_owned(),
+        ));
+    }
+    // Removal of watched roots is detected with `WatchRoot`, see `Event::is_root_changed`.
+    Ok(flags | fs::kFSEventStreamCreateFlagWatchRoot)
+}
+
+/// Nearest existing ancestor of a missing `path` (canonicalized),
+/// and `path` itself relative to the canonical ancestor.
+fn nearest_existing_ancestor(path: &Path) -> Option<(PathBuf, PathBuf)> {
+    let path = if path.is_absolute() {
+        path.to_owned()
+    } else {
+        env::current_dir().ok()?.join(path)
+    };
+    let ancestor = path.ancestors().skip(1).find(|a| a.exists())?;
+    let canonical_ancestor = ancestor.canonicalize().ok()?;
+    let canonical_path = canonical_ancestor.join(path.strip_prefix(ancestor).ok()?);
+    Some((canonical_ancestor, canonical_path))
 }
 
-struct StreamContextInfo {}
+/// Whether an event for `path` is relevant to the subtree at `prefix`.
+fn is_under(prefix: &Path, path: &Path, flags: StreamFlags, case_insensitive: bool) -> bool {
+    roots::strip_prefix(path, prefix, case_insensitive).is_some()
+        || covers_ancestor(prefix, path, flags, case_insensitive)
+}
+
+/// Rescan or removal of an ancestor also covers its descendants.
+fn covers_ancestor(
+    descendant: &Path,
+    path: &Path,
+    flags: StreamFlags,
+    case_insensitive: bool,
+) -> bool {
+    flags.intersects(StreamFlags::MUST_SCAN_SUBDIRS | StreamFlags::ROOT_CHANGED)
+        && roots::strip_prefix(descendant, path, case_insensitive).is_some()
+}
+
+struct CFSendWrapper(cf::CFRef);
+
+// Safety: wrapped objects are either handed over to another thread and not used
+// by the sender anymore, or are run loop refs used only with thread-safe calls
+// like `CFRunLoopIsWaiting` and `CFRunLoopStop`.
+unsafe impl Send for CFSendWrapper {}
+
+/// FSEvents fails to create a stream for more paths than this.
+const MAX_PATHS: usize = 4096;
+
+// From `<sys/fcntl.h>`, `<sys/param.h>` and `<sys/unistd.h>`.
+const F_GETPATH: libc::c_int = 50;
+const MAXPATHLEN: usize = 1024;
+const _PC_CASE_SENSITIVE: libc::c_int = 11;
+
+const kCFNumberSInt64Type: cf::CFIndex = 4;
+const kCFRunLoopRunFinished: i32 = 1;
+const kCFRunLoopRunStopped: i32 = 2;
+
+extern "C" {
+    /// Indicates whether the run loop is waiting for an event.
+    fn CFRunLoopIsWaiting(runloop: cf::CFRunLoopRef) -> cf::Boolean;
+
+    fn CFRunLoopRunInMode(
+        mode: cf::CFStringRef,
+        seconds: cf::CFTimeInterval,
+        return_after_source_handled: cf::Boolean,
+    ) -> i32;
+
+    fn CFErrorCopyDescription(err: cf::CFErrorRef) -> cf::CFStringRef;
+    fn CFDictionaryGetValue(the_dict: cf::CFRef, key: cf::CFRef) -> cf::CFRef;
+    fn CFNumberGetValue(
+        number: cf::CFRef,
+        the_type: cf::CFIndex,
+        value_ptr: *mut i64,
+    ) -> cf::Boolean;
+    fn CFStringGetLength(the_string: cf::CFStringRef) -> cf::CFIndex;
+    fn CFStringGetMaximumSizeForEncoding(
+        length: cf::CFIndex,
+        encoding: cf::CFStringEncoding,
+    ) -> cf::CFIndex;
+
+    static kFSEventStreamEventExtendedDataPathKey: cf::CFStringRef;
+    static kFSEventStreamEventExtendedFileIDKey: cf::CFStringRef;
+}
+
+extern "C" fn coalescing_window_ended(_timer: CFRunLoopTimerRef, info: *mut libc::c_void) {
+    let info = info as *const StreamContextInfo;
+    unsafe {
+        let mut event_handler = (*info)
+            .event_handler
+            .lock()
+            .expect("lock not to be poisoned");
+        (*info).deliver_coalesced(&mut **event_handler);
+    }
+}
 
 extern "C" fn release_context(info: *const libc::c_void) {
+    let info = info as *const StreamContextInfo;
     unsafe {
-        drop(Box::from_raw(
-            info as *const StreamContextInfo as *mut StreamContextInfo,
-        ));
+        if (*info).coalescer.is_some() {
+            let mut event_handler = (*info)
+                .event_handler
+                .lock()
+                .expect("lock not to be poisoned");
+            (*info).deliver_coalesced(&mut **event_handler);
+        }
+        drop(Box::from_raw(info as *mut StreamContextInfo));
+    }
+}
+
+extern "C" fn copy_context_description(info: *const libc::c_void) -> *const cf::CFStringRef {
+    let info = info as *const StreamContextInfo;
+    let label = unsafe { (*info).label.as_deref() }.unwrap_or(c"fsevent-dump watcher");
+    // The callback is declared to return a pointer to `CFStringRef`,
+    // but CoreFoundation expects the (copied) `CFStringRef` itself.
+    unsafe {
+        cf::CFStringCreateWithCString(
+            cf::kCFAllocatorDefault,
+            label.as_ptr(),
+            cf::kCFStringEncodingUTF8,
+        ) as *const cf::CFStringRef
     }
 }
 
 impl FsEventWatcher {
-    pub fn new() -> FsEventWatcher {
+    pub fn new<F: EventHandler>(event_handler: F) -> FsEventWatcher {
         FsEventWatcher {
             paths: unsafe {
                 cf::CFArrayCreateMutable(cf::kCFAllocatorDefault, 0, &cf::kCFTypeArrayCallBacks)
             },
             since_when: fs::kFSEventStreamEventIdSinceNow,
             latency: 0.0,
-            flags: fs::kFSEventStreamCreateFlagFileEvents | fs::kFSEventStreamCreateFlagNoDefer,
+            flags: fs::kFSEventStreamCreateFlagFileEvents
+                | fs::kFSEventStreamCreateFlagNoDefer
+                | fs::kFSEventStreamCreateFlagWatchRoot,
+            event_handler: Arc::new(Mutex::new(Box::new(event_handler))),
+            runloop: None,
+            external_runloop: false,
+            shared_runloop: None,
+            external_stream: None,
+            last_event_id: Arc::new(AtomicU64::new(0)),
+            remounted: Arc::new(AtomicBool::new(false)),
+            replay_paused_events: true,
+            max_replay_events: None,
+            deliver_history_done: true,
+            batch_ids: None,
+            paused: false,
+            roots: Vec::new(),
+            include_paths: Vec::new(),
+            ignore_globs_builder: GlobSetBuilder::new(),
+            ignore_patterns: Vec::new(),
+            ignore_globs: Arc::new(Mutex::new(GlobSet::empty())),
+            run_loop_mode: None,
+            label: None,
+            suppress_ephemeral: false,
+            ignore_hidden: false,
+            coalesce_renames: false,
+            path_decode: PathDecode::Raw,
+            interest: None,
+            follow_symlinks: true,
+            max_depth: None,
+            id_gap_threshold: None,
+            sampling: None,
+            coalescing_window: None,
+            consolidate_roots: None,
+            content_hashes: None,
+            watch_missing_paths: false,
+            file_sizes: None,
+            file_modes: None,
+            start_retries: 3,
+            start_retry_delay: Duration::from_millis(10),
+            stop_timeout: None,
+            counters: Arc::new(Counters::default()),
+            lifecycle_handler: None,
+            panic_handler: None,
+        }
+    }
+
+    /// Replace the handler events are delivered to, without restarting the stream.
+    ///
+    /// The callback holds the handler lock while it delivers a batch of events,
+    /// so this waits for the current batch to finish: events are delivered either
+    /// to the old handler or to the new one, never to both. The old handler is dropped
+    /// on the calling thread.
+    pub fn set_event_handler<F: EventHandler>(&mut self, event_handler: F) {
+        let old = mem::replace(
+            &mut *self.event_handler.lock().expect("lock not to be poisoned"),
+            Box::new(event_handler),
+        );
+        drop(old);
+    }
+
+    /// Also deliver events for which `filter` returns `true` to `event_handler`,
+    /// without restarting the stream.
+    ///
+    /// This allows several subsystems to share one stream for the same paths instead of
+    /// creating a watcher each. Every event is delivered to the handler passed to
+    /// [`new`](FsEventWatcher::new), then to matching added handlers in the order
+    /// they were added, all on the run loop thread, so a slow handler delays the others.
+    /// [`set_event_handler`](FsEventWatcher::set_event_handler) replaces all of them.
+    pub fn add_event_handler<P, F>(&mut self, filter: P, event_handler: F)
+    where
+        P: Fn(&Event) -> bool + Send + 'static,
+        F: EventHandler,
+    {
+        let mut current = self.event_handler.lock().expect("lock not to be poisoned");
+        let previous = mem::replace(&mut *current, Box::new(|_event: Event| {}));
+        *current = Box::new(Fanout {
+            previous,
+            filter: Box::new(filter),
+            handler: Box::new(event_handler),
+        });
+    }
+
+    /// Call `f` when the underlying stream is created, started, or stopped, including
+    /// restarts done by setters like [`set_latency`](FsEventWatcher::set_latency).
+    ///
+    /// `f` is called on the thread calling the watcher method which caused the transition.
+    pub fn on_lifecycle<F>(&mut self, f: F)
+    where
+        F: Fn(LifecycleEvent) + Send + 'static,
+    {
+        self.lifecycle_handler = Some(Box::new(f));
+    }
+
+    /// Call `f` with the panic message when the event handler panics.
+    ///
+    /// Panics of the handler are caught in the callback, so they do not unwind into
+    /// FSEvents, and the stream keeps delivering the next events; the event the handler
+    /// panicked on is lost. `f` is called on the run loop thread, and must not panic itself.
+    /// Panics are also logged and counted in [`WatcherStats::handler_panics`].
+    /// With `panic = "abort"` nothing can be caught.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn on_handler_panic<F>(&mut self, f: F)
+    where
+        F: Fn(String) + Send + Sync + 'static,
+    {
+        self.panic_handler = Some(Arc::new(f));
+    }
+
+    /// Start watching `path` in addition to already watched paths.
+    ///
+    /// `path` can be a directory (watched recursively) or a file.
+    /// FSEvents only watches directories, so for a file its parent directory is watched,
+    /// and events for other entries of that directory are dropped.
+    ///
+    /// On case-insensitive volumes (the default on macOS) event paths are matched to `path`
+    /// ignoring case, and reported with names as they are on disk, e.g. watching `Foo`
+    /// reports changes to the directory `foo` as `foo/...`.
+    ///
+    /// Events are delivered from a background thread, this function does not block.
+    ///
+    /// FSEvents cannot add a path to a live stream, so a running stream is recreated,
+    /// starting from the [last processed event](FsEventWatcher::last_event_id),
+    /// so no events for already watched paths are lost in between. Delivery is
+    /// at-least-once: events which arrived while the stream was being recreated may be
+    /// delivered again, followed by an [`is_history_done`](Event::is_history_done) event.
+    pub fn watch(&mut self, path: &Path) -> Result<()> {
+        self.watch_from(path, None)
+    }
+
+    /// Watch `path`, replaying events after `since_when` if set, or after the pending
+    /// [`watch_since`](FsEventWatcher::watch_since) id if the stream is not running.
+    fn watch_from(&mut self, path: &Path, since_when: Option<u64>) -> Result<()> {
+        let was_running = self.is_running();
+        self.stop();
+        let since_when = match since_when {
+            // `kFSEventStreamEventIdSinceNow` is the largest id.
+            Some(since_when) if was_running => since_when.min(self.resume_point()),
+            Some(since_when) => since_when,
+            None if was_running => self.resume_point(),
+            None => self.since_when,
+        };
+        if let Err(e) = self.append_path(path) {
+            if was_running {
+                self.run(since_when)?;
+            }
+            return Err(e);
+        }
+        self.paused = false;
+        self.run(since_when)
+    }
+
+    /// Add `path` to watched paths like [`watch`](FsEventWatcher::watch), but without
+    /// starting the stream if it is not running, so that several paths can be added,
+    /// and the stream created once by [`start`](FsEventWatcher::start).
+    ///
+    /// If the stream is running, it is recreated like by `watch`.
+    pub fn add_path(&mut self, path: &Path) -> Result<()> {
+        if self.is_running() {
+            return self.watch(path);
+        }
+        self.append_path(path)
+    }
+
+    /// Start the stream for paths added by [`add_path`](FsEventWatcher::add_path),
+    /// does nothing if the stream is running.
+    ///
+    /// The stream can be stopped by [`stop`](FsEventWatcher::stop), or by
+    /// [`pause`](FsEventWatcher::pause) and restarted by [`resume`](FsEventWatcher::resume).
+    pub fn start(&mut self) -> Result<()> {
+        if self.is_running() {
+            return Ok(());
+        }
+        if self.roots.is_empty() {
+            return Err(Error::generic("No paths to watch".to_owned()));
+        }
+        self.paused = false;
+        self.run(self.since_when)
+    }
+
+    /// Watch `path` until the first event matching `predicate`, and return that event.
+    ///
+    /// The stream is stopped before returning. Fails with [`ErrorKind::Timeout`]
+    /// if no matching event arrives within `timeout`.
+    pub fn watch_once<P>(path: &Path, timeout: Option<Duration>, mut predicate: P) -> Result<Event>
+    where
+        P: FnMut(&Event) -> bool + Send + 'static,
+    {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let mut watcher = FsEventWatcher::new(move |event: Event| {
+            if predicate(&event) {
+                // Only the first matching event is needed.
+                let _ = tx.try_send(event);
+            }
+        });
+        watcher.watch(path)?;
+        let event = match timeout {
+            Some(timeout) => rx.recv_timeout(timeout).ok(),
+            None => rx.recv().ok(),
+        };
+        event.ok_or_else(|| Error::timeout().add_path(path.to_owned()))
+    }
+
+    /// Watch `path` for `duration`, and return all events delivered meanwhile.
+    ///
+    /// Like [`watch`](FsEventWatcher::watch), the whole subtree of `path` is watched.
+    /// The stream is stopped before returning, events FSEvents had not delivered
+    /// by then are lost.
+    pub fn collect_events(path: &Path, duration: Duration) -> Result<Vec<Event>> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let mut watcher = FsEventWatcher::new(tx);
+        watcher.watch(path)?;
+        thread::sleep(duration);
+        watcher.stop();
+        Ok(rx.try_iter().collect())
+    }
+
+    /// Same as [`watch`](FsEventWatcher::watch), with `rel` relative to the directory
+    /// open as `dir_fd`.
+    ///
+    /// FSEvents is path based, so the path of the directory is resolved with
+    /// `fcntl(F_GETPATH)` once, and the resulting path is watched: if the directory
+    /// is moved later, the watch does not follow it.
+    pub fn watch_at(&mut self, dir_fd: RawFd, rel: &Path) -> Result<()> {
+        let mut buf = [0u8; MAXPATHLEN];
+        if unsafe { libc::fcntl(dir_fd, F_GETPATH, buf.as_mut_ptr()) } == -1 {
+            return Err(Error::from(io::Error::last_os_error()).add_path(rel.to_owned()));
+        }
+        let dir = CStr::from_bytes_until_nul(&buf).expect("path to be NUL-terminated");
+        let dir = Path::new(OsStr::from_bytes(dir.to_bytes()));
+        self.watch(&dir.join(rel))
+    }
+
+    /// Same as [`watch`](FsEventWatcher::watch), but also returns paths which exist
+    /// under `path` (including `path` itself) when the watch starts.
+    ///
+    /// The stream is started before the tree is walked, so there is no gap between
+    /// the snapshot and the events: a change made during the walk may be both in the snapshot
+    /// and reported as an event, so the consumer should apply events on top of the snapshot
+    /// idempotently, e.g. treat creation of an already known path as modification.
+    ///
+    /// Paths are filtered and resolved like event paths, so they respect ignore globs,
+    /// [`set_include_paths`](FsEventWatcher::set_include_paths),
+    /// [`set_ignore_hidden`](FsEventWatcher::set_ignore_hidden),
+    /// [`set_max_depth`](FsEventWatcher::set_max_depth) and
+    /// [`set_follow_symlinks`](FsEventWatcher::set_follow_symlinks).
+    /// Entries removed during the walk, or which cannot be read, are skipped.
+    pub fn watch_with_snapshot(&mut self, path: &Path) -> Result<Vec<PathBuf>> {
+        self.watch(path)?;
+        // Filtered and reported like events of the stream.
+        let context = self.context_info(fs::kFSEventStreamEventIdSinceNow);
+        let root = self.roots.last().expect("root added by watch");
+        let mut walk = WalkDir::new(&root.path).follow_links(false);
+        if let Some(max_depth) = self.max_depth {
+            walk = walk.max_depth(max_depth);
+        }
+        Ok(walk
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let file_type = entry.file_type();
+                let flags = if file_type.is_dir() {
+                    StreamFlags::IS_DIR
+                } else if file_type.is_symlink() {
+                    StreamFlags::IS_SYMLINK
+                } else {
+                    StreamFlags::IS_FILE
+                };
+                context.accepts(entry.path(), flags)
+            })
+            .map(|entry| context.report_path(entry.into_path()))
+            .collect())
+    }
+
+    /// Same as [`watch`](FsEventWatcher::watch), but also replays events
+    /// after `since_when` event id.
+    ///
+    /// Once the replay is finished, a single event with
+    /// [`is_history_done`](Event::is_history_done) is delivered,
+    /// all events after it are live. The replay happens once: later restarts of the stream
+    /// resume from the [last processed event](FsEventWatcher::last_event_id).
+    pub fn watch_since(&mut self, path: &Path, since_when: u64) -> Result<()> {
+        self.watch_from(path, Some(since_when))
+    }
+
+    /// Event id to restart a stream from: the last processed event, or the pending
+    /// [`watch_since`](FsEventWatcher::watch_since) id if nothing was processed yet.
+    fn resume_point(&self) -> u64 {
+        match self.last_event_id.load(Ordering::SeqCst) {
+            0 => self.since_when,
+            last_event_id => last_event_id,
+        }
+    }
+
+    /// Id of the last event processed by the handler (or dropped by filters).
+    ///
+    /// Before any events arrive, this is the system-wide event id when the stream started.
+    /// Persisting this value and passing it to [`watch_since`](FsEventWatcher::watch_since)
+    /// after a restart gives at-least-once processing of events.
+    pub fn last_event_id(&self) -> u64 {
+        self.last_event_id.load(Ordering::SeqCst)
+    }
+
+    /// Current system-wide FSEvents event id.
+    pub fn current_event_id() -> u64 {
+        unsafe { fs::FSEventsGetCurrentEventId() }
+    }
+
+    /// Stop watching all paths and stop the run loop thread.
+    pub fn unwatch_all(&mut self) -> Result<()> {
+        self.stop();
+        self.paused = false;
+        debug_assert!(
+            !self.is_running(),
+            "paths mutated while the stream is running"
+        );
+        unsafe {
+            for i in (0..cf::CFArrayGetCount(self.paths)).rev() {
+                cf::CFArrayRemoveValueAtIndex(self.paths, i);
+            }
+        }
+        self.roots.clear();
+        Ok(())
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.runloop.is_some() || self.external_stream.is_some()
+    }
+
+    /// Whether the run loop delivering events is waiting for them, i.e. the handler
+    /// is not running now, e.g. for tests to check the watcher has caught up.
+    ///
+    /// For a shared or external run loop, this is about all its sources, not only this watcher.
+    /// `false` if the watcher is not running.
+    pub fn is_idle(&self) -> bool {
+        let runloop = match (&self.runloop, &self.external_stream) {
+            (Some((runloop, _, _)), _) | (_, Some((_, _, runloop))) => *runloop,
+            (None, None) => return false,
+        };
+        unsafe { CFRunLoopIsWaiting(runloop) != 0 }
+    }
+
+    /// Wait until the run loop delivering events is running and has handled pending events,
+    /// e.g. for tests to make changes only after the watcher has caught up with a replay.
+    ///
+    /// Changes made after [`watch`](FsEventWatcher::watch) returns are never missed,
+    /// because the stream is started by then, and FSEvents queues events until
+    /// the loop runs. For a stream on the current run loop this returns immediately,
+    /// because the loop only runs when the caller runs it.
+    ///
+    /// Fails with [`ErrorKind::Timeout`] if the loop is still busy after `timeout`.
+    pub fn wait_until_running(&self, timeout: Duration) -> Result<()> {
+        if !self.is_running() {
+            return Err(Error::not_running());
+        }
+        if self.external_runloop {
+            return Ok(());
+        }
+        let deadline = Instant::now() + timeout;
+        while !self.is_idle() {
+            if Instant::now() >= deadline {
+                return Err(Error::timeout());
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+
+    /// Check that FSEvents actually delivers events for the watched paths, by creating
+    /// and removing a probe file in the first watched directory and waiting for an event.
+    ///
+    /// In the App Sandbox, `FSEventStreamStart` succeeds even if the app has no access
+    /// to the watched paths, but no events are ever delivered. The app needs access
+    /// to the paths, e.g. with the `com.apple.security.files.user-selected.read-only`
+    /// entitlement for paths the user picked, or a security-scoped bookmark.
+    ///
+    /// Fails with [`ErrorKind::PermissionDenied`] if no event arrives within `timeout`,
+    /// or with an I/O error if the probe file cannot be created. The probe events
+    /// are delivered to the handler like any others, unless filtered out.
+    ///
+    /// To check this manually, call this from a sandboxed app watching its container
+    /// directory, which succeeds, and a directory outside of it the app was not granted,
+    /// which fails.
+    ///
+    /// For a stream on the current run loop this always times out, because events
+    /// are only delivered when the caller runs the loop.
+    pub fn verify_delivery(&self, timeout: Duration) -> Result<()> {
+        if !self.is_running() {
+            return Err(Error::not_running());
+        }
+        let root = self.roots.first().ok_or_else(Error::not_running)?;
+        let dir = if root.is_file {
+            root.path.parent().unwrap_or(&root.path)
+        } else {
+            &root.path
+        };
+        let probe = dir.join(format!(".fsevent-dump-probe-{}", process::id()));
+        let before = self.last_event_id();
+        std_fs::File::create(&probe).map_err(|e| Error::from(e).add_path(probe.clone()))?;
+        std_fs::remove_file(&probe).map_err(|e| Error::from(e).add_path(probe.clone()))?;
+        self.flush_async()?;
+
+        let deadline = Instant::now() + timeout;
+        while self.last_event_id() == before {
+            if Instant::now() >= deadline {
+                warn!("no events delivered for {:?}", root.path);
+                return Err(Error::permission_denied().add_path(root.path.clone()));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+
+    /// Schedule the stream on a run loop thread shared with other watchers,
+    /// instead of spawning a thread per watcher.
+    ///
+    /// Events of all watchers sharing the thread are handled sequentially on it,
+    /// so a slow handler delays events of other watchers. The shared thread runs
+    /// the loop in the default mode, so [`set_run_loop_mode`](FsEventWatcher::set_run_loop_mode)
+    /// is ignored.
+    ///
+    /// A running stream is restarted on the shared thread
+    /// from the [last processed event](FsEventWatcher::last_event_id).
+    pub fn set_run_loop_thread(&mut self, runloop: Arc<RunLoopThread>) -> Result<()> {
+        let was_running = self.is_running();
+        self.stop();
+        self.shared_runloop = Some(runloop);
+        if !was_running {
+            return Ok(());
+        }
+        self.run(self.resume_point())
+    }
+
+    /// Schedule the stream on the run loop of the calling thread instead of a dedicated thread.
+    ///
+    /// The caller is responsible for running the loop (e.g. with `CFRunLoopRun`
+    /// or as the main loop of an application) in the configured
+    /// [run loop mode](FsEventWatcher::set_run_loop_mode), events are delivered from it.
+    ///
+    /// This replaces the threaded mode for the lifetime of the watcher: methods which
+    /// (re)start the stream, like [`watch`](FsEventWatcher::watch) or
+    /// [`resume`](FsEventWatcher::resume), schedule it on the run loop of the calling thread,
+    /// so they, as well as drop, must be called on the thread driving the loop.
+    ///
+    /// # Panics
+    ///
+    /// Stopping or dropping a running watcher on another thread panics, because
+    /// the loop may be delivering its events at the same time.
+    pub fn schedule_on_current_runloop(&mut self) -> Result<()> {
+        let was_running = self.is_running();
+        self.stop();
+        self.external_runloop = true;
+        if self.roots.is_empty() {
+            // Scheduled by the first `watch`.
+            return Ok(());
+        }
+        let since_when = if was_running {
+            self.resume_point()
+        } else {
+            self.since_when
+        };
+        self.run(since_when)
+    }
+
+    pub fn stats(&self) -> WatcherStats {
+        self.counters.snapshot()
+    }
+
+    /// Whether [`resume`](FsEventWatcher::resume) replays events which happened while paused.
+    ///
+    /// When `true` (the default), the stream is restarted from the last seen event id,
+    /// so changes made while paused are delivered after resume,
+    /// followed by an [`is_history_done`](Event::is_history_done) event.
+    /// When `false`, the stream is restarted from "now" and those changes are dropped.
+    pub fn set_replay_paused_events(&mut self, replay: bool) {
+        self.replay_paused_events = replay;
+    }
+
+    /// Allow [`watch`](FsEventWatcher::watch) of paths which do not exist yet.
+    ///
+    /// For such a path the nearest existing ancestor directory is watched instead,
+    /// and only events for the path and its descendants are delivered,
+    /// starting with `ITEM_CREATED` when the path appears.
+    /// Note that watching an ancestor (e.g. the home directory) may mean
+    /// many more events for FSEvents to process, even if they are dropped.
+    pub fn set_watch_missing_paths(&mut self, watch_missing_paths: bool) {
+        self.watch_missing_paths = watch_missing_paths;
+    }
+
+    /// Drop events for paths matching the glob `pattern`, e.g. `**/node_modules/**`.
+    ///
+    /// The pattern is matched against the full event path and against the path relative
+    /// to the watched root, so `node_modules/**` ignores only `node_modules` at the top
+    /// of the root. `*` does not match `/`.
+    ///
+    /// Takes effect immediately, also for a running stream.
+    pub fn add_ignore_glob(&mut self, pattern: &str) -> Result<()> {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| Error::generic(e.to_string()))?;
+        self.ignore_globs_builder.add(glob);
+        self.ignore_patterns.push(pattern.to_owned());
+        let ignore_globs = self
+            .ignore_globs_builder
+            .build()
+            .map_err(|e| Error::generic(e.to_string()))?;
+        *self.ignore_globs.lock().expect("lock not to be poisoned") = ignore_globs;
+        Ok(())
+    }
+
+    /// Remove all patterns added by [`add_ignore_glob`](FsEventWatcher::add_ignore_glob).
+    ///
+    /// Takes effect immediately, also for a running stream.
+    pub fn clear_ignore_globs(&mut self) {
+        self.ignore_globs_builder = GlobSetBuilder::new();
+        self.ignore_patterns.clear();
+        *self.ignore_globs.lock().expect("lock not to be poisoned") = GlobSet::empty();
+    }
+
+    /// Set [`Event::data_change`] for modifications of files by comparing file sizes.
+    ///
+    /// This costs a `stat` call for each file event, and memory
+    /// for the last known size of each such file, so it is disabled by default.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_track_size_changes(&mut self, track: bool) {
+        self.file_sizes = if track {
+            Some(Arc::new(Mutex::new(HashMap::new())))
+        } else {
+            None
+        };
+    }
+
+    /// Set [`Event::metadata_change`] for metadata changes by comparing the mode and the owner
+    /// of files, to tell `chmod` from `chown`, which FSEvents reports with the same flag.
+    ///
+    /// Like [`set_track_size_changes`](FsEventWatcher::set_track_size_changes), this costs
+    /// a `stat` call for each event, and memory for each file seen, so it is disabled
+    /// by default.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_track_permission_changes(&mut self, track: bool) {
+        self.file_modes = if track {
+            Some(Arc::new(Mutex::new(HashMap::new())))
+        } else {
+            None
+        };
+    }
+
+    /// Only deliver events for paths at most `max_depth` levels below the watched root,
+    /// e.g. with depth 2 events for `root/a/b` are delivered, and for `root/a/b/c` are not.
+    ///
+    /// FSEvents always watches the whole subtree, so deeper events are still
+    /// received from the kernel and filtered out in the callback.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_max_depth(&mut self, max_depth: Option<usize>) {
+        self.max_depth = max_depth;
+    }
+
+    /// Deliver a rescan event (with `MUST_SCAN_SUBDIRS | USER_DROPPED` flags)
+    /// for each watched root when the id of the first event of a batch is more than
+    /// `threshold` ahead of the last processed event id.
+    ///
+    /// Event ids are system-wide, so gaps are normal when other parts of the filesystem
+    /// change, and the threshold should be large. This is a defense against silently lost
+    /// events in addition to `KERNEL_DROPPED` and `USER_DROPPED` reported by FSEvents.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_event_id_gap_threshold(&mut self, threshold: Option<u64>) {
+        self.id_gap_threshold = threshold;
+    }
+
+    /// Forward at most `max_events` events per path per `window`, collapsing the rest into
+    /// a single summary event with `ITEM_MODIFIED` and the flags of the suppressed events,
+    /// and [`Event::collapsed`] set to their number.
+    ///
+    /// This keeps consumers responsive when a tree is very busy (e.g. a build in progress)
+    /// without falling back to a rescan, at the cost of losing the exact sequence of changes.
+    /// The summary is delivered with the first batch of events after the window ends,
+    /// so it may be delayed while the tree is quiet. Rescan events are never sampled.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_sampling(&mut self, sampling: Option<(usize, Duration)>) {
+        self.sampling = sampling;
+    }
+
+    /// Merge events for the same path within `window` after the first of them into
+    /// a single event, delivered when the window ends, or deliver events as they come if `None`.
+    ///
+    /// The merged event has the union of the flags, the id of the last event, and
+    /// [`Event::collapsed`] set to the number of events merged into it. Unlike
+    /// [`set_latency`](FsEventWatcher::set_latency), this does not depend on how FSEvents
+    /// batches events, e.g. with [`set_no_defer`](FsEventWatcher::set_no_defer).
+    /// Rescan events are delivered immediately, after the pending events.
+    ///
+    /// [`last_event_id`](FsEventWatcher::last_event_id) includes pending events,
+    /// so it may get ahead of the handler by up to `window`. Pending events are delivered
+    /// when the stream stops.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_coalescing_window(&mut self, window: Option<Duration>) {
+        self.coalescing_window = window;
+    }
+
+    /// Merge rename pairs within a single callback with the changes which followed
+    /// or preceded them, so that events reflect the net effect:
+    ///
+    /// * a file renamed and then removed is reported as removed at the old path,
+    ///   the path a consumer knows about, with no event for the new path;
+    /// * a file created and then renamed is reported as created at the new path,
+    ///   with no event for the old path;
+    /// * a rename without the other side in the same callback for a path which does not
+    ///   exist anymore, usually a move out of the watched tree, is reported as removed.
+    ///
+    /// FSEvents reports both sides of a rename as events with consecutive ids,
+    /// which is how pairs are detected. Whether a path still exists is checked when
+    /// the batch is processed, like for
+    /// [`set_suppress_ephemeral`](FsEventWatcher::set_suppress_ephemeral).
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_coalesce_renames(&mut self, coalesce: bool) {
+        self.coalesce_renames = coalesce;
+    }
+
+    /// Only deliver events with any of `kinds` flags (e.g. `ITEM_CREATED | ITEM_REMOVED`),
+    /// or all events if `None`.
+    ///
+    /// Rescans and other [events about the stream](StreamFlags::ALWAYS_DELIVERED) are always
+    /// delivered. Other events are dropped in the callback before their paths are decoded,
+    /// which is cheaper than filtering in the handler on trees with a lot of metadata churn.
+    /// Note FSEvents coalesces flags, so an event of interest may have other flags as well.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_interest(&mut self, kinds: Option<StreamFlags>) {
+        self.interest = kinds;
+    }
+
+    /// How to decode event paths which are not valid UTF-8, [`PathDecode::Raw`] by default.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_path_decode(&mut self, path_decode: PathDecode) {
+        self.path_decode = path_decode;
+    }
+
+    /// Report event paths with symlinks resolved (the default), like FSEvents does,
+    /// or under the watched path as it was passed to `watch`.
+    ///
+    /// FSEvents always resolves symlinks in a watched path, so with `/tmp` being a symlink
+    /// to `/private/tmp`, a watch of `/tmp/foo` reports `/private/tmp/foo/bar`,
+    /// or `/tmp/foo/bar` when this is disabled. Symlinks inside a watched tree are never
+    /// followed by FSEvents: changes to a symlink itself are reported, changes to its target
+    /// are not, unless the target is also watched.
+    ///
+    /// Filters such as [`add_ignore_glob`](FsEventWatcher::add_ignore_glob) match resolved paths.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_follow_symlinks(&mut self, follow: bool) {
+        self.follow_symlinks = follow;
+    }
+
+    /// Drop events for paths which were both created and removed within a single
+    /// callback and do not exist anymore, e.g. temporary files of editors doing atomic saves.
+    ///
+    /// Disabled by default, because some consumers care about such files.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_suppress_ephemeral(&mut self, suppress: bool) {
+        self.suppress_ephemeral = suppress;
+    }
+
+    /// Drop events for dot files and directories under watched paths, e.g. `.DS_Store`
+    /// or swap files of editors (disabled by default).
+    ///
+    /// Only the last path component is checked, so events for entries of a hidden
+    /// directory, e.g. `.git/index`, are still delivered, use
+    /// [`add_ignore_glob`](FsEventWatcher::add_ignore_glob) for these. Watched paths are
+    /// never dropped, even if they are hidden themselves.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_ignore_hidden(&mut self, ignore: bool) {
+        self.ignore_hidden = ignore;
+    }
+
+    /// Drop modifications of files which did not change their content,
+    /// e.g. a file saved without changes, by comparing hashes of file contents.
+    /// Inode metadata changes of a file with unchanged content and permissions,
+    /// e.g. `touch`, are dropped too.
+    ///
+    /// This reads a file in full on the callback thread for each event about it,
+    /// so it is expensive, and disabled by default. Hashes of at most `capacity` files are kept,
+    /// the least recently modified are forgotten first; the first modification of a file
+    /// without a known hash is always delivered. A modification coalesced with
+    /// another change (e.g. creation or ownership change) is always delivered.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_content_hashing(&mut self, capacity: Option<usize>) {
+        self.content_hashes = capacity.map(|capacity| Arc::new(ContentHashes::new(capacity)));
+    }
+
+    /// Whether to deliver the [`is_history_done`](Event::is_history_done) event
+    /// marking the end of a replay (enabled by default).
+    ///
+    /// Replays also happen when the watcher recreates its stream, e.g. in
+    /// [`set_latency`](FsEventWatcher::set_latency), so consumers which do not use
+    /// [`watch_since`](FsEventWatcher::watch_since) may prefer to not see these events.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_deliver_history_done(&mut self, deliver: bool) {
+        self.deliver_history_done = deliver;
+    }
+
+    /// Set [`Event::batch`] to the id of the FSEvents callback invocation which delivered
+    /// the event (disabled by default).
+    ///
+    /// FSEvents delivers events in batches, one per latency interval, and consumers
+    /// doing transactional processing may apply all changes from one batch at once.
+    /// Ids increase by one for each batch and are not reset when the stream is restarted.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_batch_ids(&mut self, enabled: bool) {
+        match (enabled, &self.batch_ids) {
+            (true, None) => self.batch_ids = Some(Arc::new(AtomicU64::new(0))),
+            (false, _) => self.batch_ids = None,
+            (true, Some(_)) => {}
+        }
+    }
+
+    /// Deliver at most `max` historical events when replaying events with
+    /// [`watch_since`](FsEventWatcher::watch_since) or [`resume`](FsEventWatcher::resume),
+    /// or all of them if `None`.
+    ///
+    /// Once the limit is reached, a rescan event (with `MUST_SCAN_SUBDIRS | USER_DROPPED`)
+    /// is delivered for each watched root, and the rest of the replay is dropped until
+    /// the [`is_history_done`](Event::is_history_done) event, after which events are live.
+    /// This bounds the startup cost for a consumer which was offline for a long time.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_max_replay_events(&mut self, max: Option<u64>) {
+        self.max_replay_events = max;
+    }
+
+    /// Only deliver events under these paths (all events if empty).
+    ///
+    /// Unlike watching each of the paths separately, this keeps a single watched root,
+    /// and drops events outside of the allowed subtrees before they reach the handler.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_include_paths(&mut self, paths: &[PathBuf]) {
+        self.include_paths = paths
+            .iter()
+            .map(|p| p.canonicalize().unwrap_or_else(|_| p.clone()))
+            .collect();
+    }
+
+    /// Interval FSEvents waits for after a change to coalesce it with subsequent changes.
+    ///
+    /// Zero (the default) means events are reported as soon as possible.
+    pub fn latency(&self) -> Duration {
+        Duration::from_secs_f64(self.latency)
+    }
+
+    /// Change the [latency](FsEventWatcher::latency).
+    ///
+    /// FSEvents cannot change latency of a live stream, so a running stream is recreated,
+    /// starting from the [last processed event](FsEventWatcher::last_event_id),
+    /// so no events are lost in between.
+    /// Like any replay, it ends with an [`is_history_done`](Event::is_history_done) event.
+    ///
+    /// FSEvents takes latency as seconds in `f64`; `Duration` is never negative or NaN,
+    /// so any value is valid.
+    pub fn set_latency(&mut self, latency: Duration) -> Result<()> {
+        self.latency = latency.as_secs_f64();
+        if !self.is_running() {
+            return Ok(());
+        }
+        self.stop();
+        self.run(self.resume_point())
+    }
+
+    /// Whether to set `kFSEventStreamCreateFlagUseExtendedData`, so that FSEvents also
+    /// reports inode numbers of entries, see [`Event::inode`].
+    ///
+    /// Inodes survive renames, so they can be used to pair both sides of a rename.
+    /// Requires macOS 10.13. Takes effect when the stream is (re)started.
+    pub fn set_extended_data(&mut self, extended_data: bool) {
+        let flags =
+            fs::kFSEventStreamCreateFlagUseCFTypes | fs::kFSEventStreamCreateFlagUseExtendedData;
+        if extended_data {
+            self.flags |= flags;
+        } else {
+            self.flags &= !flags;
+        }
+    }
+
+    /// Whether to set `kFSEventStreamCreateFlagMarkSelf`, so that changes made
+    /// by this process are reported with `OWN_EVENT` flag, see [`Event::process_id`].
+    ///
+    /// Requires macOS 10.9. Takes effect when the stream is (re)started.
+    pub fn set_mark_self(&mut self, mark_self: bool) {
+        if mark_self {
+            self.flags |= fs::kFSEventStreamCreateFlagMarkSelf;
+        } else {
+            self.flags &= !fs::kFSEventStreamCreateFlagMarkSelf;
+        }
+    }
+
+    /// Whether to set `kFSEventStreamCreateFlagNoDefer` (set by default).
+    ///
+    /// With no-defer, the first event after a quiet period is delivered immediately,
+    /// and subsequent events are batched for the latency interval.
+    /// Without it, delivery of the first event is deferred by the latency interval,
+    /// so a whole burst of changes arrives in a single callback.
+    /// With zero latency there is no difference.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_no_defer(&mut self, no_defer: bool) {
+        if no_defer {
+            self.flags |= fs::kFSEventStreamCreateFlagNoDefer;
+        } else {
+            self.flags &= !fs::kFSEventStreamCreateFlagNoDefer;
+        }
+    }
+
+    /// Watch the [common ancestor](common_ancestor) of the watched paths instead of
+    /// the paths themselves when there are more than `threshold` of them,
+    /// or always watch the paths if `None` (the default).
+    ///
+    /// Events outside of the watched paths are dropped in the callback, so this trades
+    /// more events for FSEvents to deliver and the callback to filter for fewer stream paths:
+    /// a stream is limited to a few thousand paths, and FSEvents gets slower to start
+    /// streams with many paths. Consolidating siblings like `/a/b` and `/a/c` into `/a`
+    /// is cheap, while consolidating unrelated paths may end up watching `/`.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_consolidate_roots(&mut self, threshold: Option<usize>) {
+        self.consolidate_roots = threshold;
+    }
+
+    /// Whether to set `kFSEventStreamCreateFlagFileEvents` (set by default).
+    ///
+    /// Without file events FSEvents reports changes per directory: the event path is
+    /// the directory whose entries changed, usually with no item flags, and the consumer
+    /// is expected to rescan that directory (not its subdirectories). This reduces
+    /// the volume of events dramatically, e.g. a build writing thousands of files into
+    /// a few directories produces a few events, which is sufficient for consumers which
+    /// rescan anyway.
+    ///
+    /// Watched files and options depending on item flags, like
+    /// [`set_interest`](FsEventWatcher::set_interest) or
+    /// [`set_coalesce_renames`](FsEventWatcher::set_coalesce_renames), need file events.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_file_events(&mut self, file_events: bool) {
+        if file_events {
+            self.flags |= fs::kFSEventStreamCreateFlagFileEvents;
+        } else {
+            self.flags &= !fs::kFSEventStreamCreateFlagFileEvents;
+        }
+    }
+
+    /// Human-readable label of the watcher, included in the stream description
+    /// (e.g. `FSEventStreamShow` output, crash logs and Instruments) to tell watchers apart.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_label(&mut self, label: &str) {
+        self.label = Some(CString::new(label.replace('\0', "")).expect("no NUL bytes"));
+    }
+
+    /// Run loop mode to schedule the stream in, `kCFRunLoopDefaultMode` by default.
+    ///
+    /// The watcher thread runs its loop in this mode. This only matters when the stream
+    /// shares a run loop with other sources: a loop run in a different mode
+    /// (e.g. a modal UI mode) does not service the stream, and events are delayed
+    /// until the loop runs in this mode again.
+    ///
+    /// Takes effect when the stream is (re)started.
+    pub fn set_run_loop_mode(&mut self, mode: &str) {
+        self.run_loop_mode = Some(mode.to_owned());
+    }
+
+    /// How many times to retry a failed `FSEventStreamStart`, which happens under resource pressure.
+    ///
+    /// The delay between attempts starts at `delay` and doubles after each attempt.
+    pub fn set_start_retries(&mut self, retries: u32, delay: Duration) {
+        self.start_retries = retries;
+        self.start_retry_delay = delay;
+    }
+
+    /// How long to wait for the run loop thread to stop, when the stream is stopped
+    /// or the watcher is dropped, forever by default.
+    ///
+    /// If the thread does not stop in time (e.g. the handler is stuck), it is detached
+    /// with a warning, so that application shutdown does not hang.
+    pub fn set_stop_timeout(&mut self, timeout: Option<Duration>) {
+        self.stop_timeout = timeout;
+    }
+
+    /// Whether a watched volume was unmounted and mounted again since the stream
+    /// was started, so the stream should be [restarted](FsEventWatcher::restart).
+    ///
+    /// This is detected from `UNMOUNT` and `MOUNT` events, which FSEvents reports only
+    /// for volumes mounted below a watched path, e.g. for `/Volumes/Backup` if `/Volumes`
+    /// is watched. Remounting the volume a root is on, e.g. when only
+    /// `/Volumes/Backup/project` is watched, is not reported, so it is not detected.
+    ///
+    /// When this happens, a rescan event with `MOUNT` flag is delivered for the mount point.
+    /// The stream is not restarted automatically: the callback cannot stop and recreate
+    /// the stream it is called from, so the owner of the watcher calls
+    /// [`restart`](FsEventWatcher::restart), e.g. when it receives that event.
+    pub fn needs_restart(&self) -> bool {
+        self.remounted.load(Ordering::SeqCst)
+    }
+
+    /// Recreate the stream, e.g. after a watched volume was remounted,
+    /// see [`needs_restart`](FsEventWatcher::needs_restart).
+    ///
+    /// Event ids of a remounted volume are reset, so the stream starts from now
+    /// rather than from the last processed event, and events which happened
+    /// in between are lost: the consumer should rescan the roots.
+    pub fn restart(&mut self) -> Result<()> {
+        if !self.is_running() {
+            return Ok(());
+        }
+        self.stop();
+        self.run(fs::kFSEventStreamEventIdSinceNow)
+    }
+
+    /// Stop the stream, e.g. for the duration of a bulk operation.
+    pub fn pause(&mut self) {
+        if !self.is_running() {
+            return;
+        }
+        self.stop();
+        self.paused = true;
+    }
+
+    /// Restart the stream stopped by [`pause`](FsEventWatcher::pause).
+    pub fn resume(&mut self) -> Result<()> {
+        if !self.paused {
+            return Ok(());
+        }
+        self.paused = false;
+        let since_when = if self.replay_paused_events {
+            self.resume_point()
+        } else {
+            fs::kFSEventStreamEventIdSinceNow
+        };
+        self.run(since_when)
+    }
+
+    /// Stop the stream started by [`start`](FsEventWatcher::start) or
+    /// [`watch`](FsEventWatcher::watch), keeping the watched paths, does nothing
+    /// if the stream is not running.
+    ///
+    /// Unlike [`pause`](FsEventWatcher::pause), the stream is not resumed by
+    /// [`resume`](FsEventWatcher::resume), but by `start`, and events which happen
+    /// in between are not replayed.
+    pub fn stop(&mut self) {
+        if let Some((stream, owned_mode, runloop)) = self.external_stream.take() {
+            match &self.shared_runloop {
+                // The shared thread may be in the callback of this stream right now,
+                // so the stream and its context are released there, between callbacks.
+                Some(shared_runloop) => {
+                    let stream = CFSendWrapper(stream);
+                    let owned_mode = CFSendWrapper(owned_mode);
+                    shared_runloop.perform_and_wait(Box::new(move || {
+                        // Capture the whole wrappers, not just the (non-`Send`) fields.
+                        let (stream, owned_mode) = (stream, owned_mode);
+                        unsafe { release_stream(stream.0, owned_mode.0) };
+                    }));
+                }
+                None => unsafe {
+                    // Releasing the stream while the loop may be in its callback
+                    // on another thread would free the context under the callback.
+                    assert!(
+                        cf::CFRunLoopGetCurrent() == runloop,
+                        "watcher scheduled on the current run loop must be stopped \
+                         and dropped on the thread which scheduled it"
+                    );
+                    release_stream(stream, owned_mode)
+                },
+            }
+            self.notify_lifecycle(LifecycleEvent::Stopped);
+        }
+        if let Some((runloop, _stream, thread_handle)) = self.runloop.take() {
+            let deadline = self.stop_timeout.map(|timeout| Instant::now() + timeout);
+            let timed_out = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+            unsafe {
+                while CFRunLoopIsWaiting(runloop) == 0 && !timed_out() {
+                    thread::yield_now();
+                }
+                cf::CFRunLoopStop(runloop);
+            }
+            if deadline.is_some() {
+                while !thread_handle.is_finished() && !timed_out() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                if !thread_handle.is_finished() {
+                    // The thread releases the stream itself if it ever gets unstuck.
+                    warn!("run loop thread did not stop in time, detaching it");
+                    self.notify_lifecycle(LifecycleEvent::Stopped);
+                    return;
+                }
+            }
+            thread_handle.join().expect("thread to shut down");
+            debug!("stream stopped");
+            self.notify_lifecycle(LifecycleEvent::Stopped);
+        }
+    }
+
+    fn notify_lifecycle(&self, event: LifecycleEvent) {
+        if let Some(lifecycle_handler) = &self.lifecycle_handler {
+            lifecycle_handler(event);
         }
     }
 
-    pub fn watch(&mut self, path: &Path) {
-        self.append_path(path);
-        self.run();
+    fn append_path(&mut self, path: &Path) -> Result<()> {
+        debug_assert!(
+            !self.is_running(),
+            "paths mutated while the stream is running"
+        );
+        let (canonical_path, stream_path, is_file) = if path.exists() {
+            let canonical_path = path
+                .canonicalize()
+                .map_err(|e| Error::from(e).add_path(path.to_owned()))?;
+            if canonical_path.is_dir() {
+                (canonical_path, path.to_owned(), false)
+            } else {
+                let parent = canonical_path
+                    .parent()
+                    .expect("file to have a parent")
+                    .to_owned();
+                (canonical_path, parent, true)
+            }
+        } else if self.watch_missing_paths {
+            let (ancestor, canonical_path) = nearest_existing_ancestor(path)
+                .ok_or_else(|| Error::path_not_found().add_path(path.to_owned()))?;
+            (canonical_path, ancestor, false)
+        } else {
+            return Err(Error::path_not_found().add_path(path.to_owned()));
+        };
+        // Ancestor directory may be already passed to FSEvents for another path.
+        let watches_ancestor = stream_path != path;
+        if !watches_ancestor || !self.watched_paths().contains(&stream_path) {
+            if self.consolidate_roots.is_none()
+                && unsafe { cf::CFArrayGetCount(self.paths) } as usize >= MAX_PATHS
+            {
+                return Err(Error::max_paths_exceeded().add_path(path.to_owned()));
+            }
+            let str_path = path_to_str(&stream_path)?;
+            unsafe {
+                let mut err: cf::CFErrorRef = ptr::null_mut();
+                let cf_path = cf::str_path_to_cfstring_ref(str_path, &mut err);
+                if cf_path.is_null() {
+                    // Most likely the directory was deleted, or permissions changed,
+                    // while the above code was running.
+                    if err.is_null() {
+                        return Err(Error::path_not_found().add_path(path.to_owned()));
+                    }
+                    let description = CFErrorCopyDescription(err);
+                    cf::CFRelease(err as cf::CFRef);
+                    let description = cfstring_into_string(description);
+                    return Err(Error::generic(description).add_path(path.to_owned()));
+                }
+                cf::CFArrayAppendValue(self.paths, cf_path);
+                cf::CFRelease(cf_path);
+            }
+        }
+        let original = env::current_dir()
+            .map(|cwd| cwd.join(path))
+            .unwrap_or_else(|_| canonical_path.clone());
+        let case_insensitive = !is_case_sensitive(&stream_path);
+        self.roots.push(WatchRoot {
+            path: canonical_path,
+            original,
+            is_file,
+            case_insensitive,
+        });
+        Ok(())
     }
 
-    fn append_path(&mut self, path: &Path) {
-        assert!(path.exists());
-        let str_path = path.to_str().unwrap();
+    /// Array with the common ancestor of the watched paths, if there are more of them than
+    /// the [consolidation](FsEventWatcher::set_consolidate_roots) threshold.
+    fn consolidated_paths(&self) -> Result<Option<cf::CFMutableArrayRef>> {
+        let threshold = match self.consolidate_roots {
+            Some(threshold) => threshold,
+            None => return Ok(None),
+        };
+        let paths = self.watched_paths();
+        if paths.len() <= threshold {
+            return Ok(None);
+        }
+        let ancestor = common_ancestor(&paths).expect("watched paths to be absolute");
+        debug!("consolidating {} paths into {:?}", paths.len(), ancestor);
+        let str_path = path_to_str(&ancestor)?;
         unsafe {
             let mut err: cf::CFErrorRef = ptr::null_mut();
             let cf_path = cf::str_path_to_cfstring_ref(str_path, &mut err);
             if cf_path.is_null() {
-                // Most likely the directory was deleted, or permissions changed,
-                // while the above code was running.
-                cf::CFRelease(err as cf::CFRef);
-                panic!("path not found");
+                if !err.is_null() {
+                    cf::CFRelease(err as cf::CFRef);
+                }
+                return Err(Error::path_not_found().add_path(ancestor));
             }
-            cf::CFArrayAppendValue(self.paths, cf_path);
+            let array =
+                cf::CFArrayCreateMutable(cf::kCFAllocatorDefault, 1, &cf::kCFTypeArrayCallBacks);
+            cf::CFArrayAppendValue(array, cf_path);
             cf::CFRelease(cf_path);
+            Ok(Some(array))
+        }
+    }
+
+    /// Stream of the watcher, if running.
+    fn stream(&self) -> Option<fs::FSEventStreamRef> {
+        match (&self.runloop, &self.external_stream) {
+            (Some((_, stream, _)), _) | (_, Some((stream, _, _))) => Some(*stream),
+            (None, None) => None,
+        }
+    }
+
+    /// The underlying stream, if running, for calling FSEvents APIs this crate does not wrap.
+    ///
+    /// # Safety
+    ///
+    /// The watcher owns the stream: it must not be released, stopped, invalidated
+    /// or rescheduled, and must not be used after the watcher is stopped, reconfigured
+    /// in a way which recreates the stream, or dropped.
+    pub unsafe fn raw_stream(&self) -> Option<fs::FSEventStreamRef> {
+        self.stream()
+    }
+
+    /// Ask FSEvents to deliver events collected so far without waiting for the latency
+    /// interval, and return the id of the last of them.
+    ///
+    /// Unlike `FSEventStreamFlushSync`, this does not block until the events are handled:
+    /// they are delivered on the run loop thread as usual, and the consumer can wait for
+    /// [`last_event_id`](FsEventWatcher::last_event_id) to reach the returned id.
+    pub fn flush_async(&self) -> Result<u64> {
+        let stream = self.stream().ok_or_else(Error::not_running)?;
+        Ok(unsafe { fs::FSEventStreamFlushAsync(stream) })
+    }
+
+    /// Paths the running stream watches, as reported by FSEvents.
+    ///
+    /// Unlike [`watched_paths`](FsEventWatcher::watched_paths), which are paths
+    /// passed to FSEvents, these are paths after FSEvents resolved them, so this
+    /// can be used to diagnose paths which are not watched as expected.
+    pub fn os_watched_paths(&self) -> Result<Vec<PathBuf>> {
+        let stream = self.stream().ok_or_else(Error::not_running)?;
+        unsafe {
+            let paths = fs::FSEventStreamCopyPathsBeingWatched(stream);
+            if paths.is_null() {
+                return Ok(Vec::new());
+            }
+            let result = (0..cf::CFArrayGetCount(paths))
+                .map(|i| cfstring_to_path_buf(cf::CFArrayGetValueAtIndex(paths, i)))
+                .collect();
+            cf::CFRelease(paths);
+            Ok(result)
+        }
+    }
+
+    /// Whether events for `path` are delivered, because it is a watched path or
+    /// it is under a watched directory (FSEvents watches are always recursive).
+    ///
+    /// Only watched paths are considered, not filters like
+    /// [`add_ignore_glob`](FsEventWatcher::add_ignore_glob). `path` does not have to exist.
+    pub fn is_watched(&self, path: &Path) -> bool {
+        let path = match fsevents_path(path) {
+            Some(path) => path,
+            None => return false,
+        };
+        RootTrie::new(&self.roots).matches(&path, StreamFlags::empty())
+    }
+
+    /// Paths passed to FSEvents, in the order they were added.
+    ///
+    /// FSEvents watches are always recursive, so there is no per-path mode.
+    pub fn watched_paths(&self) -> Vec<PathBuf> {
+        let count = unsafe { cf::CFArrayGetCount(self.paths) };
+        (0..count)
+            .map(|i| unsafe { cfstring_to_path_buf(cf::CFArrayGetValueAtIndex(self.paths, i)) })
+            .collect()
+    }
+
+    /// Configuration of the watcher to persist, see [`WatcherConfig`].
+    ///
+    /// Once the stream has started, `since_when` is the
+    /// [last processed event id](FsEventWatcher::last_event_id), so a watcher
+    /// restored with [`from_config`](FsEventWatcher::from_config) resumes where this one is.
+    pub fn to_config(&self) -> WatcherConfig {
+        WatcherConfig {
+            paths: self
+                .roots
+                .iter()
+                .map(|root| root.original.clone())
+                .collect(),
+            latency: self.latency(),
+            create_flags: self.flags,
+            ignore_globs: self.ignore_patterns.clone(),
+            include_paths: self.include_paths.clone(),
+            since_when: match self.last_event_id() {
+                0 => self.since_when,
+                last_event_id => last_event_id,
+            },
+        }
+    }
+
+    /// Create a watcher from a configuration taken by [`to_config`](FsEventWatcher::to_config),
+    /// and start watching its paths, if any.
+    ///
+    /// Fails if `create_flags` has flags or combinations of flags which cannot be set
+    /// through the watcher's setters.
+    pub fn from_config<F: EventHandler>(
+        config: &WatcherConfig,
+        event_handler: F,
+    ) -> Result<FsEventWatcher> {
+        let mut watcher = FsEventWatcher::new(event_handler);
+        watcher.latency = config.latency.as_secs_f64();
+        watcher.flags = supported_create_flags(config.create_flags)?;
+        for pattern in &config.ignore_globs {
+            watcher.add_ignore_glob(pattern)?;
+        }
+        watcher.set_include_paths(&config.include_paths);
+        watcher.since_when = config.since_when;
+        for path in &config.paths {
+            watcher.add_path(path)?;
+        }
+        if !config.paths.is_empty() {
+            watcher.start()?;
+        }
+        Ok(watcher)
+    }
+
+    fn run(&mut self, since_when: fs::FSEventStreamEventId) -> Result<()> {
+        let result = self.start_stream(since_when);
+        if result.is_ok() {
+            // The replay is now tracked by `last_event_id`, restarts resume from there
+            // instead of replaying from the `watch_since` id again.
+            self.since_when = fs::kFSEventStreamEventIdSinceNow;
+        }
+        self.notify_lifecycle(match result {
+            Ok(()) => LifecycleEvent::Started,
+            Err(_) => LifecycleEvent::StartFailed,
+        });
+        result
+    }
+
+    /// State of a stream starting from `since_when`, passed to the callback.
+    fn context_info(&self, since_when: fs::FSEventStreamEventId) -> StreamContextInfo {
+        StreamContextInfo {
+            event_handler: self.event_handler.clone(),
+            last_event_id: self.last_event_id.clone(),
+            roots: self.roots.clone(),
+            include_paths: self
+                .include_paths
+                .iter()
+                .map(|path| {
+                    let existing = path.ancestors().find(|a| a.exists()).unwrap_or(path);
+                    (path.clone(), !is_case_sensitive(existing))
+                })
+                .collect(),
+            ignore_globs: self.ignore_globs.clone(),
+            counters: self.counters.clone(),
+            file_sizes: self.file_sizes.clone(),
+            file_modes: self.file_modes.clone(),
+            label: self.label.clone(),
+            suppress_ephemeral: self.suppress_ephemeral,
+            ignore_hidden: self.ignore_hidden,
+            follow_symlinks: self.follow_symlinks,
+            max_depth: self.max_depth,
+            extended_data: self.flags & fs::kFSEventStreamCreateFlagUseExtendedData != 0,
+            id_gap_threshold: self.id_gap_threshold,
+            sampler: self
+                .sampling
+                .map(|(max_events, window)| Sampler::new(max_events, window)),
+            coalescer: self.coalescing_window.map(Coalescer::new),
+            content_hashes: self.content_hashes.clone(),
+            coalesce_renames: self.coalesce_renames,
+            path_decode: self.path_decode,
+            interest: self.interest,
+            root_trie: RootTrie::new(&self.roots),
+            unmounted: Mutex::new(HashSet::new()),
+            remounted: self.remounted.clone(),
+            replay_budget: match self.max_replay_events {
+                Some(max) if since_when != fs::kFSEventStreamEventIdSinceNow => {
+                    Some(Mutex::new(ReplayBudget::Left(max)))
+                }
+                _ => None,
+            },
+            deliver_history_done: self.deliver_history_done,
+            batch_ids: self.batch_ids.clone(),
+            panic_handler: self.panic_handler.clone(),
         }
     }
 
-    fn run(&mut self) {
+    fn start_stream(&mut self, since_when: fs::FSEventStreamEventId) -> Result<()> {
+        self.remounted.store(false, Ordering::SeqCst);
         if unsafe { cf::CFArrayGetCount(self.paths) } == 0 {
             panic!("no paths to watch");
         }
 
-        let context = Box::into_raw(Box::new(StreamContextInfo {}));
+        if since_when == fs::kFSEventStreamEventIdSinceNow {
+            // So that `resume` has a starting point even if no events arrive before `pause`.
+            self.last_event_id
+                .store(unsafe { fs::FSEventsGetCurrentEventId() }, Ordering::SeqCst);
+        } else {
+            // Nothing after `since_when` is processed yet, so a restart before the replay
+            // catches up resumes from here.
+            self.last_event_id.store(since_when, Ordering::SeqCst);
+        }
+
+        debug!(
+            "creating stream for {:?} since {:#x}",
+            self.watched_paths(),
+            since_when
+        );
+
+        let consolidated = self.consolidated_paths()?;
+        let context = Box::into_raw(Box::new(self.context_info(since_when)));
 
         let stream_context = fs::FSEventStreamContext {
             version: 0,
             info: context as *mut libc::c_void,
             retain: None,
             release: Some(release_context),
-            copy_description: None,
+            copy_description: Some(copy_context_description),
         };
 
         let stream = unsafe {
@@ -109,27 +2122,230 @@ impl FsEventWatcher {
                 cf::kCFAllocatorDefault,
                 callback,
                 &stream_context,
-                self.paths,
-                self.since_when,
+                consolidated.unwrap_or(self.paths),
+                since_when,
                 self.latency,
                 self.flags,
             )
         };
+        if let Some(consolidated) = consolidated {
+            // The stream has its own copy.
+            unsafe { cf::CFRelease(consolidated) };
+        }
+        if stream.is_null() {
+            release_context(context as *const libc::c_void);
+            return Err(Error::generic("FSEventStreamCreate failed".to_owned()));
+        }
+        self.notify_lifecycle(LifecycleEvent::Created);
+
+        // Null if the default mode is used.
+        let owned_mode = match &self.run_loop_mode {
+            Some(mode) => {
+                let mode = CString::new(mode.as_str()).expect("run loop mode without NUL bytes");
+                unsafe {
+                    cf::CFStringCreateWithCString(
+                        cf::kCFAllocatorDefault,
+                        mode.as_ptr(),
+                        cf::kCFStringEncodingUTF8,
+                    )
+                }
+            }
+            None => ptr::null_mut(),
+        };
+        let start_retries = self.start_retries;
+        let start_retry_delay = self.start_retry_delay;
 
-        unsafe {
-            let cur_runloop = cf::CFRunLoopGetCurrent();
+        if let Some(shared_runloop) = &self.shared_runloop {
+            // The shared thread only runs the default mode.
+            if !owned_mode.is_null() {
+                unsafe { cf::CFRelease(owned_mode) };
+            }
+            let owned_mode = ptr::null_mut();
+            unsafe {
+                if !schedule_and_start(
+                    stream,
+                    shared_runloop.runloop(),
+                    owned_mode,
+                    start_retries,
+                    start_retry_delay,
+                ) {
+                    return Err(Error::stream_start_failed());
+                }
+            }
+            self.external_stream = Some((stream, owned_mode, shared_runloop.runloop()));
+            return Ok(());
+        }
+
+        if self.external_runloop {
+            let runloop = unsafe { cf::CFRunLoopGetCurrent() };
+            unsafe {
+                if !schedule_and_start(
+                    stream,
+                    runloop,
+                    owned_mode,
+                    start_retries,
+                    start_retry_delay,
+                ) {
+                    return Err(Error::stream_start_failed());
+                }
+            }
+            self.external_stream = Some((stream, owned_mode, runloop));
+            return Ok(());
+        }
+
+        let stream_ref = stream;
+        let stream = CFSendWrapper(stream);
+        let owned_mode = CFSendWrapper(owned_mode);
+
+        let (rl_tx, rl_rx) = crossbeam_channel::bounded(1);
+        let thread_handle = thread::Builder::new()
+            .name("fsevent-dump loop".to_owned())
+            .spawn(move || {
+                // Capture the whole wrappers, not just the (non-`Send`) fields.
+                let stream = stream;
+                let stream = stream.0;
+                let owned_mode = owned_mode;
+                let owned_mode = owned_mode.0;
+                unsafe {
+                    if !schedule_and_start(
+                        stream,
+                        cf::CFRunLoopGetCurrent(),
+                        owned_mode,
+                        start_retries,
+                        start_retry_delay,
+                    ) {
+                        rl_tx
+                            .send(Err(Error::stream_start_failed()))
+                            .expect("Unable to send runloop to watcher");
+                        return;
+                    }
+
+                    rl_tx
+                        .send(Ok(CFSendWrapper(cf::CFRunLoopGetCurrent())))
+                        .expect("Unable to send runloop to watcher");
+                    // The loop is stopped by `CFRunLoopStop` called from `stop`.
+                    let mode = run_loop_mode_or_default(owned_mode);
+                    loop {
+                        match CFRunLoopRunInMode(mode, 1.0e10, 0) {
+                            kCFRunLoopRunStopped | kCFRunLoopRunFinished => break,
+                            _ => {}
+                        }
+                    }
+                    release_stream(stream, owned_mode);
+                }
+            })
+            .expect("Unable to spawn runloop thread");
+
+        match rl_rx.recv().unwrap() {
+            Ok(runloop) => {
+                self.runloop = Some((runloop.0, stream_ref, thread_handle));
+                Ok(())
+            }
+            Err(e) => {
+                thread_handle.join().expect("thread to shut down");
+                Err(e)
+            }
+        }
+    }
+}
+
+fn run_loop_mode_or_default(owned_mode: cf::CFStringRef) -> cf::CFStringRef {
+    if owned_mode.is_null() {
+        unsafe { cf::kCFRunLoopDefaultMode }
+    } else {
+        owned_mode
+    }
+}
+
+/// Schedule the stream on the run loop and start it, retrying failed starts.
+///
+/// On failure the stream and the mode are released.
+unsafe fn schedule_and_start(
+    stream: fs::FSEventStreamRef,
+    runloop: cf::CFRunLoopRef,
+    owned_mode: cf::CFStringRef,
+    start_retries: u32,
+    mut start_retry_delay: Duration,
+) -> bool {
+    fs::FSEventStreamScheduleWithRunLoop(stream, runloop, run_loop_mode_or_default(owned_mode));
 
-            fs::FSEventStreamScheduleWithRunLoop(stream, cur_runloop, cf::kCFRunLoopDefaultMode);
-            fs::FSEventStreamStart(stream);
-            cf::CFRunLoopRun();
-            fs::FSEventStreamStop(stream);
+    let mut attempt = 0;
+    while fs::FSEventStreamStart(stream) == 0 {
+        if attempt == start_retries {
+            warn!("FSEventStreamStart failed, giving up");
             fs::FSEventStreamInvalidate(stream);
             fs::FSEventStreamRelease(stream);
+            if !owned_mode.is_null() {
+                cf::CFRelease(owned_mode);
+            }
+            return false;
+        }
+        warn!(
+            "FSEventStreamStart failed, retrying in {:?}",
+            start_retry_delay
+        );
+        thread::sleep(start_retry_delay);
+        start_retry_delay *= 2;
+        attempt += 1;
+    }
+    debug!("stream started");
+    true
+}
+
+unsafe fn release_stream(stream: fs::FSEventStreamRef, owned_mode: cf::CFStringRef) {
+    fs::FSEventStreamStop(stream);
+    fs::FSEventStreamInvalidate(stream);
+    fs::FSEventStreamRelease(stream);
+    debug!("stream released");
+    if !owned_mode.is_null() {
+        cf::CFRelease(owned_mode);
+    }
+}
+
+impl Drop for FsEventWatcher {
+    fn drop(&mut self) {
+        self.stop();
+        unsafe {
+            cf::CFRelease(self.paths);
         }
-        panic!("no");
     }
 }
 
+unsafe fn cfstring_to_path_buf(cf_str: cf::CFStringRef) -> PathBuf {
+    let c_str = cf::CFStringGetCStringPtr(cf_str, cf::kCFStringEncodingUTF8);
+    if !c_str.is_null() {
+        return PathBuf::from(OsStr::from_bytes(CStr::from_ptr(c_str).to_bytes()));
+    }
+
+    let mut buf = [0 as libc::c_char; libc::PATH_MAX as usize];
+    if !cf::CFStringGetCString(
+        cf_str,
+        buf.as_mut_ptr(),
+        buf.len() as cf::CFIndex,
+        cf::kCFStringEncodingUTF8,
+    ) {
+        panic!("Unable to convert CFString to path");
+    }
+    PathBuf::from(OsStr::from_bytes(CStr::from_ptr(buf.as_ptr()).to_bytes()))
+}
+
+/// Convert a CFString to a `String` and release it.
+unsafe fn cfstring_into_string(cf_str: cf::CFStringRef) -> String {
+    if cf_str.is_null() {
+        return String::new();
+    }
+    let size =
+        CFStringGetMaximumSizeForEncoding(CFStringGetLength(cf_str), cf::kCFStringEncodingUTF8) + 1;
+    let mut buf = vec![0 as libc::c_char; size as usize];
+    let converted =
+        cf::CFStringGetCString(cf_str, buf.as_mut_ptr(), size, cf::kCFStringEncodingUTF8);
+    cf::CFRelease(cf_str);
+    if !converted {
+        return String::new();
+    }
+    CStr::from_ptr(buf.as_ptr()).to_string_lossy().into_owned()
+}
+
 extern "C" fn callback(
     stream_ref: fs::FSEventStreamRef,
     info: *mut libc::c_void,
@@ -150,27 +2366,838 @@ extern "C" fn callback(
     }
 }
 
+/// Event decoded from the callback arguments, before filtering.
+struct RawEvent {
+    path: PathBuf,
+    flags: StreamFlags,
+    raw_flags: u32,
+    id: u64,
+    inode: Option<u64>,
+}
+
+/// Path from the bytes FSEvents reports, `None` if it is not UTF-8 and `path_decode` is strict.
+fn decode_path(path: &[u8], path_decode: PathDecode) -> Option<PathBuf> {
+    match path_decode {
+        PathDecode::Raw => Some(PathBuf::from(OsStr::from_bytes(path))),
+        PathDecode::Lossy => Some(PathBuf::from(String::from_utf8_lossy(path).into_owned())),
+        PathDecode::Strict => Some(PathBuf::from(str::from_utf8(path).ok()?)),
+    }
+}
+
+/// Path of the `index`-th event, and its inode if FSEvents reports extended data.
+///
+/// `event_paths` is an array of C strings, or with extended data a `CFArray`
+/// of dictionaries. `None` if FSEvents did not provide a path,
+/// or it is not UTF-8 and `path_decode` is strict.
+unsafe fn event_path(
+    event_paths: *mut libc::c_void,
+    index: usize,
+    extended_data: bool,
+    path_decode: PathDecode,
+) -> Option<(PathBuf, Option<u64>)> {
+    if !extended_data {
+        let path = *(event_paths as *const *const libc::c_char).add(index);
+        if path.is_null() {
+            return None;
+        }
+        let path = decode_path(CStr::from_ptr(path).to_bytes(), path_decode)?;
+        return Some((path, None));
+    }
+    // CFString is Unicode, so paths of extended data are always UTF-8.
+    let dict = cf::CFArrayGetValueAtIndex(event_paths, index as cf::CFIndex);
+    if dict.is_null() {
+        return None;
+    }
+    let path = CFDictionaryGetValue(dict, kFSEventStreamEventExtendedDataPathKey);
+    if path.is_null() {
+        return None;
+    }
+    let path = cfstring_to_path_buf(path);
+    let file_id = CFDictionaryGetValue(dict, kFSEventStreamEventExtendedFileIDKey);
+    let mut inode = 0i64;
+    let inode =
+        if !file_id.is_null() && CFNumberGetValue(file_id, kCFNumberSInt64Type, &mut inode) != 0 {
+            Some(inode as u64)
+        } else {
+            None
+        };
+    Some((path, inode))
+}
+
 unsafe fn callback_impl(
     _stream_ref: fs::FSEventStreamRef,
-    _info: *mut libc::c_void,
+    info: *mut libc::c_void,
     num_events: libc::size_t,                        // size_t numEvents
     event_paths: *mut libc::c_void,                  // void *eventPaths
     event_flags: *const fs::FSEventStreamEventFlags, // const FSEventStreamEventFlags eventFlags[]
-    _event_ids: *const fs::FSEventStreamEventId,     // const FSEventStreamEventId eventIds[]
+    event_ids: *const fs::FSEventStreamEventId,      // const FSEventStreamEventId eventIds[]
 ) {
-    let event_paths = event_paths as *const *const libc::c_char;
+    let info = info as *const StreamContextInfo;
+    let last_event_id = &(*info).last_event_id;
+    let counters = &(*info).counters;
 
+    trace!("callback with {} events", num_events);
+    if num_events == 0 || event_paths.is_null() {
+        return;
+    }
+    let mut event_handler = (*info)
+        .event_handler
+        .lock()
+        .expect("lock not to be poisoned");
+    let batch = (*info)
+        .batch_ids
+        .as_ref()
+        .map(|batch_ids| batch_ids.fetch_add(1, Ordering::SeqCst));
+
+    let mut events = Vec::with_capacity(num_events);
     for p in 0..num_events {
-        let path = CStr::from_ptr(*event_paths.add(p))
-            .to_str()
-            .expect("Invalid UTF8 string.");
-        let path = PathBuf::from(path);
-
-        let flag = *event_flags.add(p);
-        let flag = StreamFlags::from_bits(flag).unwrap_or_else(|| {
-            panic!("Unable to decode StreamFlags: {}", flag);
+        let id = *event_ids.add(p);
+        let raw_flags = *event_flags.add(p);
+        // Unknown flags, e.g. added in a newer macOS, are kept in `raw_flags`.
+        let flags = StreamFlags::from_bits_truncate(raw_flags);
+        counters.raw();
+        if !(*info).interested_in(flags) {
+            counters.dropped();
+            last_event_id.fetch_max(id, Ordering::SeqCst);
+            continue;
+        }
+
+        let path = event_path(event_paths, p, (*info).extended_data, (*info).path_decode);
+        let (path, inode) = match path {
+            Some(path) => path,
+            None => {
+                warn!("no valid path for event {:#x}, skipping", id);
+                counters.dropped();
+                last_event_id.fetch_max(id, Ordering::SeqCst);
+                continue;
+            }
+        };
+
+        events.push(RawEvent {
+            path,
+            flags,
+            raw_flags,
+            id,
+            inode,
         });
+    }
+
+    if let (Some(threshold), Some(first)) = ((*info).id_gap_threshold, events.first()) {
+        let first_id = first.id;
+        let previous = last_event_id.load(Ordering::SeqCst);
+        if previous != 0 && first_id.saturating_sub(previous) > threshold {
+            warn!(
+                "event id gap from {:#x} to {:#x}, requesting rescan",
+                previous, first_id
+            );
+            let flags = StreamFlags::MUST_SCAN_SUBDIRS | StreamFlags::USER_DROPPED;
+            for root in &(*info).roots {
+                (*info).deliver_synthetic(
+                    &mut **event_handler,
+                    Event {
+                        path: (*info).report_path(root.path.clone()),
+                        flags,
+                        raw_flags: flags.bits(),
+                        id: first_id,
+                        data_change: None,
+                        metadata_change: None,
+                        inode: None,
+                        collapsed: 0,
+                        batch,
+                    },
+                );
+            }
+        }
+    }
+
+    if let Some(sampler) = &(*info).sampler {
+        for mut summary in sampler.take_expired() {
+            summary.path = (*info).report_path(summary.path);
+            summary.batch = batch;
+            (*info).deliver_synthetic(&mut **event_handler, summary);
+        }
+    }
+
+    let events = if (*info).coalesce_renames {
+        let received = events.len();
+        let events = coalesce_renames(events);
+        for _ in events.len()..received {
+            counters.dropped();
+        }
+        events
+    } else {
+        events
+    };
+
+    let ephemeral = if (*info).suppress_ephemeral {
+        ephemeral_paths(&events)
+    } else {
+        HashSet::new()
+    };
+    let subsumed = subsumed_rescans(&events, |event| {
+        !ephemeral.contains(&event.path) && (*info).accepts(&event.path, event.flags)
+    });
+
+    for RawEvent {
+        path,
+        flags: flag,
+        raw_flags,
+        id,
+        inode,
+    } in events
+    {
+        trace!("raw event: {:?} {:?} {:#x}", path, flag, id);
+
+        // Mount points may be ancestors of roots, so this is checked before filtering.
+        for remounted in (*info).remounted_paths(&path, flag) {
+            debug!("volume of {:?} was remounted", remounted);
+            let flags = StreamFlags::MUST_SCAN_SUBDIRS | StreamFlags::MOUNT;
+            (*info).deliver_synthetic(
+                &mut **event_handler,
+                Event {
+                    path: (*info).report_path(remounted),
+                    flags,
+                    raw_flags: flags.bits(),
+                    id,
+                    data_change: None,
+                    metadata_change: None,
+                    inode: None,
+                    collapsed: 0,
+                    batch,
+                },
+            );
+        }
+
+        if ephemeral.contains(&path) || subsumed.contains(&id) || !(*info).accepts(&path, flag) {
+            counters.dropped();
+            last_event_id.fetch_max(id, Ordering::SeqCst);
+            continue;
+        }
+
+        if let Some(roots) = (*info).exceeds_replay_budget(flag) {
+            for root in roots {
+                let flags = StreamFlags::MUST_SCAN_SUBDIRS | StreamFlags::USER_DROPPED;
+                (*info).deliver_synthetic(
+                    &mut **event_handler,
+                    Event {
+                        path: (*info).report_path(root.path.clone()),
+                        flags,
+                        raw_flags: flags.bits(),
+                        id,
+                        data_change: None,
+                        metadata_change: None,
+                        inode: None,
+                        collapsed: 0,
+                        batch,
+                    },
+                );
+            }
+            counters.dropped();
+            last_event_id.fetch_max(id, Ordering::SeqCst);
+            continue;
+        }
+
+        if flag.contains(StreamFlags::HISTORY_DONE) && !(*info).deliver_history_done {
+            counters.dropped();
+            last_event_id.fetch_max(id, Ordering::SeqCst);
+            continue;
+        }
+
+        if let Some(content_hashes) = &(*info).content_hashes {
+            if content_hashes.is_unchanged(&path, flag) {
+                counters.dropped();
+                last_event_id.fetch_max(id, Ordering::SeqCst);
+                continue;
+            }
+        }
+
+        if let Some(sampler) = &(*info).sampler {
+            if !flag.intersects(StreamFlags::MUST_SCAN_SUBDIRS | StreamFlags::HISTORY_DONE)
+                && !sampler.admit(&path, flag, id)
+            {
+                counters.dropped();
+                last_event_id.fetch_max(id, Ordering::SeqCst);
+                continue;
+            }
+        }
+
+        // For `MUST_SCAN_SUBDIRS` the path is the root of the subtree to rescan,
+        // so it is delivered as is, like any other event.
+        if flag.contains(StreamFlags::MUST_SCAN_SUBDIRS) {
+            debug!("rescan required for {:?}: {:?}", path, flag);
+        }
+        let data_change = (*info).track_size(&path, flag);
+        let metadata_change = (*info).track_mode(&path, flag);
+        let path = (*info).report_path(path);
+        let event = Event {
+            path,
+            flags: flag,
+            raw_flags,
+            id,
+            data_change,
+            metadata_change,
+            inode,
+            collapsed: 0,
+            batch,
+        };
+        match &(*info).coalescer {
+            Some(coalescer)
+                if !flag.intersects(StreamFlags::MUST_SCAN_SUBDIRS | StreamFlags::HISTORY_DONE) =>
+            {
+                if coalescer.push(event, coalescing_window_ended, info as *mut libc::c_void) {
+                    counters.dropped();
+                }
+            }
+            _ => {
+                (*info).deliver_coalesced(&mut **event_handler);
+                (*info).deliver(&mut **event_handler, event);
+            }
+        }
+        // Updated after the handler returns, so the watermark never gets ahead of processing.
+        last_event_id.fetch_max(id, Ordering::SeqCst);
+    }
+}
+
+/// Merge rename pairs with creation before or removal after the rename,
+/// see [`FsEventWatcher::set_coalesce_renames`].
+fn coalesce_renames(events: Vec<RawEvent>) -> Vec<RawEvent> {
+    let mut result = Vec::with_capacity(events.len());
+    let mut events = events.into_iter().peekable();
+    while let Some(from) = events.next() {
+        let to = match events.peek() {
+            Some(to)
+                if from.flags.contains(StreamFlags::ITEM_RENAMED)
+                    && to.flags.contains(StreamFlags::ITEM_RENAMED)
+                    && to.id == from.id + 1
+                    && std_fs::symlink_metadata(&from.path).is_err() =>
+            {
+                events.next().expect("peeked")
+            }
+            // Moved out of the watched tree, or the other side is in another batch.
+            _ if from.flags.contains(StreamFlags::ITEM_RENAMED)
+                && std_fs::symlink_metadata(&from.path).is_err() =>
+            {
+                let flags = (from.flags - StreamFlags::ITEM_RENAMED) | StreamFlags::ITEM_REMOVED;
+                result.push(RawEvent {
+                    flags,
+                    raw_flags: flags.bits() | (from.raw_flags & !StreamFlags::all().bits()),
+                    ..from
+                });
+                continue;
+            }
+            _ => {
+                result.push(from);
+                continue;
+            }
+        };
+        let created = from.flags.contains(StreamFlags::ITEM_CREATED);
+        let removed = std_fs::symlink_metadata(&to.path).is_err();
+        match (created, removed) {
+            // Created, renamed and removed: nothing happened.
+            (true, true) => {}
+            (false, true) => {
+                let flags = (from.flags - StreamFlags::ITEM_RENAMED) | StreamFlags::ITEM_REMOVED;
+                result.push(RawEvent {
+                    flags,
+                    raw_flags: flags.bits() | (from.raw_flags & !StreamFlags::all().bits()),
+                    id: to.id,
+                    ..from
+                });
+            }
+            (true, false) => {
+                let flags = (to.flags - StreamFlags::ITEM_RENAMED) | StreamFlags::ITEM_CREATED;
+                result.push(RawEvent {
+                    flags,
+                    raw_flags: flags.bits() | (to.raw_flags & !StreamFlags::all().bits()),
+                    ..to
+                });
+            }
+            (false, false) => {
+                result.push(from);
+                result.push(to);
+            }
+        }
+    }
+    result
+}
+
+/// Paths created and removed within a batch which do not exist anymore,
+/// so the net effect of their events is nothing.
+///
+/// FSEvents coalesces flags, so the order of creation and removal is not known,
+/// hence the check that the path is gone: a file removed and then recreated is a replace.
+fn ephemeral_paths(events: &[RawEvent]) -> HashSet<PathBuf> {
+    let mut seen: HashMap<&Path, StreamFlags> = HashMap::new();
+    for event in events {
+        *seen.entry(&event.path).or_insert_with(StreamFlags::empty) |= event.flags;
+    }
+    seen.into_iter()
+        .filter(|(path, flag)| {
+            flag.contains(StreamFlags::ITEM_CREATED | StreamFlags::ITEM_REMOVED)
+                && !flag.contains(StreamFlags::HISTORY_DONE)
+                && std_fs::symlink_metadata(path).is_err()
+        })
+        .map(|(path, _)| path.to_owned())
+        .collect()
+}
+
+/// Ids of rescan events within a batch for paths under another rescan event of the batch,
+/// e.g. when a large directory is moved, which the rescan of the ancestor subsumes.
+///
+/// Only rescans for which `passes` returns true can subsume others, so that
+/// a filtered out ancestor does not take its descendants with it.
+fn subsumed_rescans(events: &[RawEvent], passes: impl Fn(&RawEvent) -> bool) -> HashSet<u64> {
+    let mut rescans: Vec<_> = events
+        .iter()
+        .filter(|event| event.flags.contains(StreamFlags::MUST_SCAN_SUBDIRS) && passes(event))
+        .map(|event| (&event.path, event.id))
+        .collect();
+    // Paths are ordered by components, so descendants follow their ancestor.
+    rescans.sort();
+    let mut subsumed = HashSet::new();
+    let mut ancestor: Option<&Path> = None;
+    for (path, id) in rescans {
+        match ancestor {
+            Some(ancestor) if path.starts_with(ancestor) => {
+                subsumed.insert(id);
+            }
+            _ => ancestor = Some(path),
+        }
+    }
+    subsumed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(path: &Path, flags: StreamFlags, id: u64) -> RawEvent {
+        RawEvent {
+            path: path.to_owned(),
+            flags,
+            raw_flags: flags.bits(),
+            id,
+            inode: None,
+        }
+    }
+
+    /// Empty directory unique to the test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("fsevent-dump-{}-{}", name, std::process::id()));
+        let _ = std_fs::remove_dir_all(&dir);
+        std_fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Context of a stream watching `roots`, with no filters set.
+    fn context(roots: &[&str]) -> StreamContextInfo {
+        let roots: Vec<WatchRoot> = roots
+            .iter()
+            .map(|root| WatchRoot {
+                path: PathBuf::from(root),
+                original: PathBuf::from(root),
+                is_file: false,
+                case_insensitive: false,
+            })
+            .collect();
+        StreamContextInfo {
+            event_handler: Arc::new(Mutex::new(Box::new(|_: Event| {}))),
+            last_event_id: Arc::new(AtomicU64::new(0)),
+            root_trie: RootTrie::new(&roots),
+            roots,
+            include_paths: Vec::new(),
+            ignore_globs: Arc::new(Mutex::new(GlobSet::empty())),
+            counters: Arc::new(Counters::default()),
+            file_sizes: None,
+            file_modes: None,
+            label: None,
+            suppress_ephemeral: false,
+            ignore_hidden: false,
+            follow_symlinks: true,
+            max_depth: None,
+            extended_data: false,
+            id_gap_threshold: None,
+            sampler: None,
+            coalescer: None,
+            content_hashes: None,
+            coalesce_renames: false,
+            path_decode: PathDecode::Raw,
+            interest: None,
+            unmounted: Mutex::new(HashSet::new()),
+            remounted: Arc::new(AtomicBool::new(false)),
+            replay_budget: None,
+            deliver_history_done: false,
+            batch_ids: None,
+            panic_handler: None,
+        }
+    }
+
+    #[test]
+    fn flag_names() {
+        assert_eq!(StreamFlags::empty().names(), Vec::<&str>::new());
+        assert_eq!(
+            (StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE).names(),
+            vec!["ITEM_CREATED", "IS_FILE"]
+        );
+        assert_eq!(
+            (StreamFlags::IS_DIR | StreamFlags::MUST_SCAN_SUBDIRS | StreamFlags::USER_DROPPED)
+                .names(),
+            vec!["MUST_SCAN_SUBDIRS", "USER_DROPPED", "IS_DIR"]
+        );
+    }
+
+    #[test]
+    fn common_ancestor_of_paths() {
+        assert_eq!(common_ancestor(&[]), None);
+        assert_eq!(
+            common_ancestor(&[PathBuf::from("/a/b")]),
+            Some(PathBuf::from("/a/b"))
+        );
+        assert_eq!(
+            common_ancestor(&[
+                PathBuf::from("/a/b/c"),
+                PathBuf::from("/a/bc"),
+                PathBuf::from("/a/b")
+            ]),
+            Some(PathBuf::from("/a"))
+        );
+        assert_eq!(
+            common_ancestor(&[PathBuf::from("/a"), PathBuf::from("/b")]),
+            Some(PathBuf::from("/"))
+        );
+        assert_eq!(
+            common_ancestor(&[PathBuf::from("a"), PathBuf::from("b")]),
+            None
+        );
+    }
+
+    #[test]
+    fn create_flags_from_config() {
+        let defaults = fs::kFSEventStreamCreateFlagFileEvents
+            | fs::kFSEventStreamCreateFlagNoDefer
+            | fs::kFSEventStreamCreateFlagWatchRoot;
+        assert_eq!(supported_create_flags(defaults).unwrap(), defaults);
+        assert_eq!(
+            supported_create_flags(fs::kFSEventStreamCreateFlagFileEvents).unwrap(),
+            fs::kFSEventStreamCreateFlagFileEvents | fs::kFSEventStreamCreateFlagWatchRoot
+        );
+        let extended_data = defaults
+            | fs::kFSEventStreamCreateFlagUseCFTypes
+            | fs::kFSEventStreamCreateFlagUseExtendedData;
+        assert_eq!(
+            supported_create_flags(extended_data).unwrap(),
+            extended_data
+        );
+
+        assert!(supported_create_flags(defaults | fs::kFSEventStreamCreateFlagUseCFTypes).is_err());
+        assert!(
+            supported_create_flags(defaults | fs::kFSEventStreamCreateFlagUseExtendedData).is_err()
+        );
+        assert!(supported_create_flags(defaults | fs::kFSEventStreamCreateFlagIgnoreSelf).is_err());
+        assert!(supported_create_flags(defaults | 0x8000_0000).is_err());
+    }
+
+    #[test]
+    fn ignore_globs() {
+        let context = context(&["/r"]);
+        let file = StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE;
+        for pattern in ["**/node_modules/**", "node_modules/**"] {
+            let glob = GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .unwrap();
+            *context.ignore_globs.lock().unwrap() =
+                GlobSetBuilder::new().add(glob).build().unwrap();
+            assert!(!context.accepts(Path::new("/r/node_modules/x"), file));
+            assert!(context.accepts(Path::new("/r/node_modules_backup/x"), file));
+            assert!(context.accepts(Path::new("/r/x"), file));
+        }
+        // Relative patterns are anchored at the root.
+        assert!(context.accepts(Path::new("/r/a/node_modules/x"), file));
+    }
+
+    #[test]
+    fn max_depth() {
+        let mut context = context(&["/r"]);
+        context.max_depth = Some(2);
+        let file = StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE;
+        assert!(context.accepts(Path::new("/r"), StreamFlags::ITEM_MODIFIED));
+        assert!(context.accepts(Path::new("/r/a/b"), file));
+        assert!(!context.accepts(Path::new("/r/a/b/c"), file));
+        // Rescan of an ancestor of the root is not under it.
+        assert!(context.accepts(Path::new("/"), StreamFlags::MUST_SCAN_SUBDIRS));
+    }
+
+    #[test]
+    fn hidden_entries() {
+        let mut context = context(&["/x", "/x/.hidden", "/home/.config"]);
+        context.ignore_hidden = true;
+        let file = StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE;
+        let dir = StreamFlags::ITEM_MODIFIED | StreamFlags::IS_DIR;
+        assert!(!context.accepts(Path::new("/x/.DS_Store"), file));
+        assert!(!context.accepts(Path::new("/x/a/.DS_Store"), file));
+        assert!(context.accepts(Path::new("/x/a/file"), file));
+        assert!(context.accepts(Path::new("/home/.config"), dir));
+        assert!(context.accepts(Path::new("/home/.config/file"), file));
+        assert!(context.accepts(Path::new("/x/.hidden"), dir));
+        assert!(context.accepts(Path::new("/x/.hidden/file"), file));
+        assert!(!context.accepts(Path::new("/x/.hidden/.git"), dir));
+    }
+
+    #[test]
+    fn path_decode_modes() {
+        let invalid = b"/r/\xffname";
+        assert_eq!(
+            decode_path(invalid, PathDecode::Raw)
+                .unwrap()
+                .as_os_str()
+                .as_bytes(),
+            invalid
+        );
+        assert_eq!(
+            decode_path(invalid, PathDecode::Lossy),
+            Some(PathBuf::from("/r/\u{fffd}name"))
+        );
+        assert_eq!(decode_path(invalid, PathDecode::Strict), None);
+        for path_decode in [PathDecode::Raw, PathDecode::Lossy, PathDecode::Strict] {
+            assert_eq!(
+                decode_path(b"/r/name", path_decode),
+                Some(PathBuf::from("/r/name"))
+            );
+        }
+
+        let invalid = Path::new(OsStr::from_bytes(invalid));
+        let err = path_to_str(invalid).unwrap_err();
+        assert_eq!(err.paths, [invalid]);
+    }
+
+    #[test]
+    fn remounted_volumes() {
+        let below = context(&["/Volumes", "/other"]);
+        let volume = Path::new("/Volumes/Backup");
+        let mount = StreamFlags::MOUNT | StreamFlags::IS_DIR;
+        let unmount = StreamFlags::UNMOUNT | StreamFlags::IS_DIR;
+
+        // Mounted for the first time.
+        assert!(below.remounted_paths(volume, mount).is_empty());
+        assert!(below.remounted_paths(volume, unmount).is_empty());
+        assert!(below
+            .remounted_paths(volume, StreamFlags::ITEM_CREATED | StreamFlags::IS_DIR)
+            .is_empty());
+        assert!(!below.remounted.load(Ordering::SeqCst));
+        assert_eq!(below.remounted_paths(volume, mount), [volume]);
+        assert!(below.remounted.load(Ordering::SeqCst));
+        // Only once per unmount.
+        assert!(below.remounted_paths(volume, mount).is_empty());
+
+        // Not watched.
+        let elsewhere = Path::new("/mnt/usb");
+        assert!(below.remounted_paths(elsewhere, unmount).is_empty());
+        assert!(below.remounted_paths(elsewhere, mount).is_empty());
+
+        // Mounted above a root.
+        let above = context(&["/Volumes/Backup/project"]);
+        assert!(above.remounted_paths(volume, unmount).is_empty());
+        assert_eq!(
+            above.remounted_paths(volume, mount),
+            [Path::new("/Volumes/Backup/project")]
+        );
+    }
+
+    #[test]
+    fn include_paths() {
+        let mut context = context(&["/r"]);
+        context.include_paths = vec![(PathBuf::from("/r/keep"), false)];
+        let file = StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE;
+        assert!(context.accepts(Path::new("/r/keep"), StreamFlags::ITEM_CREATED));
+        assert!(context.accepts(Path::new("/r/keep/file"), file));
+        assert!(!context.accepts(Path::new("/r/drop/file"), file));
+        assert!(!context.accepts(Path::new("/r/keeper"), file));
+        // Rescan of an ancestor covers the include path.
+        assert!(context.accepts(Path::new("/r"), StreamFlags::MUST_SCAN_SUBDIRS));
+        assert!(!context.accepts(Path::new("/r/drop"), StreamFlags::MUST_SCAN_SUBDIRS));
+    }
+
+    #[test]
+    fn device_of_files_in_same_dir() {
+        let dir = temp_dir("device");
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        std_fs::write(&a, b"").unwrap();
+        std_fs::write(&b, b"").unwrap();
+        assert_eq!(device_for_path(&a).unwrap(), device_for_path(&b).unwrap());
+
+        let missing = dir.join("missing");
+        let err = device_for_path(&missing).unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::PathNotFound));
+        assert_eq!(err.paths, [missing]);
+
+        std_fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn handler_panics_are_caught() {
+        let mut context = context(&["/r"]);
+        let panics = Arc::new(Mutex::new(Vec::new()));
+        let reported = panics.clone();
+        context.panic_handler = Some(Arc::new(move |message| {
+            reported.lock().unwrap().push(message);
+        }));
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut handler = {
+            let received = received.clone();
+            move |event: Event| {
+                if event.id == 1 {
+                    panic!("handler failed");
+                }
+                received.lock().unwrap().push(event.id);
+            }
+        };
+        let file = StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE;
+        for id in 1..=2 {
+            let event = Event {
+                path: PathBuf::from("/r/file"),
+                flags: file,
+                raw_flags: file.bits(),
+                id,
+                data_change: None,
+                metadata_change: None,
+                inode: None,
+                collapsed: 0,
+                batch: None,
+            };
+            context.deliver(&mut handler, event);
+        }
+
+        assert_eq!(*panics.lock().unwrap(), ["handler failed"]);
+        assert_eq!(*received.lock().unwrap(), [2]);
+        let stats = context.counters.snapshot();
+        assert_eq!(stats.handler_panics, 1);
+        assert_eq!(stats.forwarded_events, 2);
+    }
+
+    #[test]
+    fn replay_budget() {
+        let mut limited = context(&["/r"]);
+        limited.replay_budget = Some(Mutex::new(ReplayBudget::Left(2)));
+        let file = StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE;
+        assert!(limited.exceeds_replay_budget(file).is_none());
+        assert!(limited.exceeds_replay_budget(file).is_none());
+        // The first event over the limit requests a rescan of the roots.
+        let roots = limited.exceeds_replay_budget(file).unwrap();
+        assert_eq!(
+            roots.iter().map(|root| &root.path).collect::<Vec<_>>(),
+            [Path::new("/r")]
+        );
+        assert!(limited.exceeds_replay_budget(file).unwrap().is_empty());
+        // Live events after the replay are delivered again.
+        assert!(limited
+            .exceeds_replay_budget(StreamFlags::HISTORY_DONE)
+            .is_none());
+        assert!(limited.exceeds_replay_budget(file).is_none());
+
+        // Not limited.
+        let unlimited = context(&["/r"]);
+        for _ in 0..10 {
+            assert!(unlimited.exceeds_replay_budget(file).is_none());
+        }
+    }
+
+    #[test]
+    fn coalesce_renames_pairs() {
+        let dir = temp_dir("coalesce-renames");
+        let existing = dir.join("existing");
+        std_fs::write(&existing, b"").unwrap();
+        let gone = dir.join("gone");
+        let renamed = StreamFlags::ITEM_RENAMED | StreamFlags::IS_FILE;
+
+        // Plain rename: both sides are kept.
+        let events = coalesce_renames(vec![raw(&gone, renamed, 1), raw(&existing, renamed, 2)]);
+        assert_eq!(events.len(), 2);
+
+        // Created and renamed: a creation of the destination.
+        let events = coalesce_renames(vec![
+            raw(&gone, renamed | StreamFlags::ITEM_CREATED, 1),
+            raw(&existing, renamed, 2),
+        ]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].path, existing);
+        assert_eq!(events[0].id, 2);
+        assert_eq!(
+            events[0].flags,
+            StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE
+        );
+
+        // Renamed and removed: a removal of the source.
+        let also_gone = dir.join("also-gone");
+        let events = coalesce_renames(vec![raw(&gone, renamed, 1), raw(&also_gone, renamed, 2)]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].path, gone);
+        assert_eq!(events[0].id, 2);
+        assert_eq!(
+            events[0].flags,
+            StreamFlags::ITEM_REMOVED | StreamFlags::IS_FILE
+        );
+
+        // Created, renamed and removed: nothing.
+        let events = coalesce_renames(vec![
+            raw(&gone, renamed | StreamFlags::ITEM_CREATED, 1),
+            raw(&also_gone, renamed, 2),
+        ]);
+        assert!(events.is_empty());
+
+        // Moved out of the watched tree: a removal.
+        let events = coalesce_renames(vec![raw(&gone, renamed, 1)]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].flags,
+            StreamFlags::ITEM_REMOVED | StreamFlags::IS_FILE
+        );
+
+        // Not consecutive ids, so not a pair.
+        let events = coalesce_renames(vec![raw(&existing, renamed, 1), raw(&existing, renamed, 3)]);
+        assert_eq!(events.len(), 2);
+
+        std_fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ephemeral_paths_are_gone() {
+        let dir = temp_dir("ephemeral");
+        let existing = dir.join("existing");
+        std_fs::write(&existing, b"").unwrap();
+        let gone = dir.join("gone");
+        let events = vec![
+            raw(&gone, StreamFlags::ITEM_CREATED, 1),
+            raw(&gone, StreamFlags::ITEM_REMOVED, 2),
+            // Removed and recreated.
+            raw(
+                &existing,
+                StreamFlags::ITEM_CREATED | StreamFlags::ITEM_REMOVED,
+                3,
+            ),
+            // Only removed.
+            raw(&dir.join("removed"), StreamFlags::ITEM_REMOVED, 4),
+        ];
+        assert_eq!(ephemeral_paths(&events), HashSet::from([gone]));
+        std_fs::remove_dir_all(&dir).unwrap();
+    }
 
-        println!("{:?} {:?}", path, flag);
+    #[test]
+    fn subsumed_rescans_of_descendants() {
+        let rescan = StreamFlags::MUST_SCAN_SUBDIRS;
+        let events = vec![
+            raw(Path::new("/a/b"), rescan, 1),
+            raw(Path::new("/a"), rescan, 2),
+            raw(Path::new("/ab"), rescan, 3),
+            raw(Path::new("/a/c"), StreamFlags::ITEM_CREATED, 4),
+            raw(Path::new("/a/b/c"), rescan, 5),
+        ];
+        assert_eq!(subsumed_rescans(&events, |_| true), HashSet::from([1, 5]));
+        // Filtered out ancestor does not subsume anything.
+        assert_eq!(
+            subsumed_rescans(&events, |event| event.path != Path::new("/a")),
+            HashSet::from([5])
+        );
     }
 }
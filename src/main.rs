@@ -1,16 +1,66 @@
 use std::env;
 use std::path::Path;
+use std::thread;
+
+use fsevent_dump::Event;
+use fsevent_dump::FsEventWatcher;
+
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+fn kind_color(kind: &str) -> Option<&'static str> {
+    match kind {
+        "RESCAN" | "ROOT" | "REMOVE" => Some(RED),
+        "CREATE" => Some(GREEN),
+        "RENAME" | "MODIFY" | "META" => Some(YELLOW),
+        _ => None,
+    }
+}
+
+fn print_event(event: &Event, color: bool) {
+    let kind = event.kind_name();
+    let (start, end) = match kind_color(kind) {
+        Some(kind_color) if color => (kind_color, RESET),
+        _ => ("", ""),
+    };
+    println!(
+        "{:>20} {}{:<7}{} {} [{}]",
+        event.id,
+        start,
+        kind,
+        end,
+        event.path.display(),
+        event.flags.names().join(" ")
+    );
+}
 
 fn main() {
     let args = env::args().collect::<Vec<_>>();
-    let path = match args.as_slice() {
-        [_, path] => path,
-        _ => panic!("Usage: {} <path>", env::args().next().unwrap()),
+    let (path, color) = match args.as_slice() {
+        [_, path] => (path, false),
+        [_, flag, path] if flag == "--color" => (path, true),
+        [_, flag, path] if flag == "--wait" => {
+            // Wait for the first event, print it and exit.
+            let event = FsEventWatcher::watch_once(Path::new(path), None, |_| true)
+                .expect("Unable to watch");
+            print_event(&event, false);
+            return;
+        }
+        _ => panic!(
+            "Usage: {} [--color | --wait] <path>",
+            env::args().next().unwrap()
+        ),
     };
 
-    let mut watcher = fsevent_dump::FsEventWatcher::new();
+    let mut watcher = FsEventWatcher::new(move |event: Event| {
+        print_event(&event, color);
+    });
 
-    watcher.watch(Path::new(path));
+    watcher.watch(Path::new(path)).expect("Unable to watch");
 
-    unreachable!();
+    loop {
+        thread::park();
+    }
 }
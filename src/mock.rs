@@ -0,0 +1,96 @@
+use std::sync::Mutex;
+
+use crate::Event;
+use crate::EventHandler;
+
+/// Watcher which does not touch the filesystem, and delivers scripted events instead.
+///
+/// Allows testing event handling code without depending on FSEvents timing:
+///
+/// ```
+/// # use std::path::PathBuf;
+/// # use fsevent_dump::{Event, MockWatcher, StreamFlags};
+/// let (tx, rx) = crossbeam_channel::unbounded();
+/// let watcher = MockWatcher::new(tx);
+/// watcher.push(Event {
+///     path: PathBuf::from("/tmp/foo"),
+///     flags: StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE,
+///     raw_flags: (StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE).bits(),
+///     id: 1,
+///     data_change: None,
+///     metadata_change: None,
+///     inode: None,
+///     collapsed: 0,
+///     batch: None,
+/// });
+/// assert!(rx.try_recv().unwrap().is_create());
+/// ```
+pub struct MockWatcher {
+    event_handler: Mutex<Box<dyn EventHandler>>,
+}
+
+impl MockWatcher {
+    pub fn new<F: EventHandler>(event_handler: F) -> MockWatcher {
+        MockWatcher {
+            event_handler: Mutex::new(Box::new(event_handler)),
+        }
+    }
+
+    /// Deliver `event` to the handler on the calling thread.
+    pub fn push(&self, event: Event) {
+        self.event_handler
+            .lock()
+            .expect("lock not to be poisoned")
+            .handle_event(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::StreamFlags;
+
+    fn event(flags: StreamFlags, id: u64) -> Event {
+        Event {
+            path: PathBuf::from("/tmp/foo"),
+            flags,
+            raw_flags: flags.bits(),
+            id,
+            data_change: None,
+            metadata_change: None,
+            inode: None,
+            collapsed: 0,
+            batch: None,
+        }
+    }
+
+    #[test]
+    fn scripted_sequence() {
+        // Handler tracking which files exist, like a consumer would.
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let watcher = MockWatcher::new(move |event: Event| {
+            let state = if event.is_remove() {
+                "removed"
+            } else if event.is_create() {
+                "created"
+            } else if event.is_modify() {
+                "modified"
+            } else {
+                "other"
+            };
+            tx.send((event.id, state)).unwrap();
+        });
+
+        watcher.push(event(StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE, 1));
+        // Delivered synchronously.
+        assert_eq!(rx.try_recv().unwrap(), (1, "created"));
+        watcher.push(event(StreamFlags::ITEM_MODIFIED | StreamFlags::IS_FILE, 2));
+        watcher.push(event(StreamFlags::ITEM_REMOVED | StreamFlags::IS_FILE, 3));
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            [(2, "modified"), (3, "removed")]
+        );
+    }
+}
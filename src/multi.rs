@@ -0,0 +1,52 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use crate::Event;
+use crate::FsEventWatcher;
+use crate::Result;
+use crate::RunLoopThread;
+
+/// Several independent watchers delivering events to a single handler.
+///
+/// Each event is passed to the handler with the index of the watcher it came from,
+/// which is the order of [`watch`](MultiWatcher::watch) calls. All watchers share
+/// a single [`RunLoopThread`], so the handler is never called concurrently.
+pub struct MultiWatcher {
+    runloop: Arc<RunLoopThread>,
+    event_handler: Arc<Mutex<dyn FnMut(usize, Event) + Send>>,
+    watchers: Vec<FsEventWatcher>,
+}
+
+impl MultiWatcher {
+    pub fn new<F>(event_handler: F) -> MultiWatcher
+    where
+        F: FnMut(usize, Event) + Send + 'static,
+    {
+        MultiWatcher {
+            runloop: RunLoopThread::spawn(),
+            event_handler: Arc::new(Mutex::new(event_handler)),
+            watchers: Vec::new(),
+        }
+    }
+
+    /// Start watching `path` with a new watcher, and return its index.
+    pub fn watch(&mut self, path: &Path) -> Result<usize> {
+        let index = self.watchers.len();
+        let event_handler = self.event_handler.clone();
+        let mut watcher = FsEventWatcher::new(move |event: Event| {
+            let mut event_handler = event_handler.lock().expect("lock not to be poisoned");
+            (*event_handler)(index, event);
+        });
+        watcher.set_run_loop_thread(self.runloop.clone())?;
+        watcher.watch(path)?;
+        self.watchers.push(watcher);
+        Ok(index)
+    }
+
+    /// Watchers in the order of [`watch`](MultiWatcher::watch) calls,
+    /// e.g. to configure or inspect them individually.
+    pub fn watchers_mut(&mut self) -> &mut [FsEventWatcher] {
+        &mut self.watchers
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::path::Path;
+
+use crate::StreamFlags;
+use crate::WatchRoot;
+
+/// Watched roots indexed by path components, so that matching an event path
+/// against roots costs O(depth of the path) rather than O(number of roots).
+#[derive(Default)]
+pub(crate) struct RootTrie {
+    /// A directory is watched at this node, so everything below matches.
+    dir: bool,
+    /// A file is watched at this node, so only the node itself matches.
+    file: bool,
+    children: HashMap<OsString, RootTrie>,
+    /// Children for roots on case-insensitive volumes, by lowercase name.
+    case_insensitive_children: HashMap<OsString, RootTrie>,
+}
+
+impl RootTrie {
+    pub(crate) fn new(roots: &[WatchRoot]) -> RootTrie {
+        let mut trie = RootTrie::default();
+        for root in roots {
+            let mut node = &mut trie;
+            for component in root.path.components() {
+                let component = component.as_os_str();
+                node = if root.case_insensitive {
+                    node.case_insensitive_children
+                        .entry(lowercase(component))
+                        .or_default()
+                } else {
+                    node.children.entry(component.to_owned()).or_default()
+                };
+            }
+            if root.is_file {
+                node.file = true;
+            } else {
+                node.dir = true;
+            }
+        }
+        trie
+    }
+
+    /// Whether an event for `path` is relevant to any root: it is under a watched
+    /// directory, is a watched file, or is a rescan or removal of an ancestor of a root.
+    ///
+    /// Paths are compared ignoring case for roots on case-insensitive volumes,
+    /// because FSEvents reports names as they are on disk, which may differ in case
+    /// from the watched path.
+    pub(crate) fn matches(&self, path: &Path, flags: StreamFlags) -> bool {
+        let components: Vec<&OsStr> = path.components().map(|c| c.as_os_str()).collect();
+        self.matches_components(&components, flags)
+    }
+
+    fn matches_components(&self, components: &[&OsStr], flags: StreamFlags) -> bool {
+        let (component, rest) = match components.split_first() {
+            Some(split) => split,
+            // Nodes only exist on paths to roots, so the path is a root or an ancestor
+            // of one, and rescan or removal of an ancestor covers its descendants.
+            None => {
+                return self.dir
+                    || self.file
+                    || flags.intersects(StreamFlags::MUST_SCAN_SUBDIRS | StreamFlags::ROOT_CHANGED)
+            }
+        };
+        if self.dir {
+            return true;
+        }
+        // A prefix can be shared by roots on volumes of both kinds, e.g. `/`, so both are tried.
+        if let Some(child) = self.children.get(*component) {
+            if child.matches_components(rest, flags) {
+                return true;
+            }
+        }
+        if self.case_insensitive_children.is_empty() {
+            return false;
+        }
+        match self.case_insensitive_children.get(&lowercase(component)) {
+            Some(child) => child.matches_components(rest, flags),
+            None => false,
+        }
+    }
+}
+
+/// `path` relative to `prefix`, like [`Path::strip_prefix`], comparing components
+/// ignoring case if `case_insensitive`. The rest keeps the case of `path`.
+pub(crate) fn strip_prefix<'a>(
+    path: &'a Path,
+    prefix: &Path,
+    case_insensitive: bool,
+) -> Option<&'a Path> {
+    let mut components = path.components();
+    for prefix_component in prefix.components() {
+        let component = components.next()?.as_os_str();
+        let prefix_component = prefix_component.as_os_str();
+        if component != prefix_component
+            && !(case_insensitive && lowercase(component) == lowercase(prefix_component))
+        {
+            return None;
+        }
+    }
+    Some(components.as_path())
+}
+
+fn lowercase(name: &OsStr) -> OsString {
+    OsString::from(name.to_string_lossy().to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn root(path: &str, is_file: bool, case_insensitive: bool) -> WatchRoot {
+        WatchRoot {
+            path: PathBuf::from(path),
+            original: PathBuf::from(path),
+            is_file,
+            case_insensitive,
+        }
+    }
+
+    #[test]
+    fn matches_roots() {
+        let trie = RootTrie::new(&[root("/a/b", false, false), root("/x/f", true, false)]);
+        let created = StreamFlags::ITEM_CREATED;
+        assert!(trie.matches(Path::new("/a/b"), created));
+        assert!(trie.matches(Path::new("/a/b/c/d"), created));
+        assert!(!trie.matches(Path::new("/a/bc"), created));
+        assert!(!trie.matches(Path::new("/a/B/c"), created));
+        assert!(trie.matches(Path::new("/x/f"), created));
+        assert!(!trie.matches(Path::new("/x/f/g"), created));
+        assert!(!trie.matches(Path::new("/x/g"), created));
+        // Ancestors only match for rescans and root changes.
+        assert!(!trie.matches(Path::new("/a"), created));
+        assert!(trie.matches(Path::new("/a"), StreamFlags::MUST_SCAN_SUBDIRS));
+        assert!(trie.matches(Path::new("/x"), StreamFlags::ROOT_CHANGED));
+    }
+
+    #[test]
+    fn matches_ignoring_case() {
+        let trie = RootTrie::new(&[root("/Users/Foo", false, true), root("/Data", false, false)]);
+        let created = StreamFlags::ITEM_CREATED;
+        assert!(trie.matches(Path::new("/Users/Foo/bar"), created));
+        assert!(trie.matches(Path::new("/users/FOO/bar"), created));
+        assert!(!trie.matches(Path::new("/users/food"), created));
+        assert!(trie.matches(Path::new("/Data/x"), created));
+        assert!(!trie.matches(Path::new("/data/x"), created));
+    }
+
+    #[test]
+    fn strip_prefix_ignoring_case() {
+        assert_eq!(
+            strip_prefix(Path::new("/Users/foo/Bar"), Path::new("/users/FOO"), true),
+            Some(Path::new("Bar"))
+        );
+        assert_eq!(
+            strip_prefix(Path::new("/Users/foo/Bar"), Path::new("/users/FOO"), false),
+            None
+        );
+        assert_eq!(
+            strip_prefix(Path::new("/a/b"), Path::new("/a/b"), false),
+            Some(Path::new(""))
+        );
+        assert_eq!(strip_prefix(Path::new("/a"), Path::new("/a/b"), true), None);
+    }
+}
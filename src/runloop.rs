@@ -0,0 +1,165 @@
+use std::ptr;
+use std::sync::Arc;
+use std::thread;
+
+use fsevent_sys::core_foundation as cf;
+use log::debug;
+
+use crate::kCFRunLoopRunFinished;
+use crate::kCFRunLoopRunStopped;
+use crate::CFRunLoopIsWaiting;
+use crate::CFRunLoopRunInMode;
+use crate::CFSendWrapper;
+
+pub(crate) type CFRunLoopTimerRef = cf::CFRef;
+
+#[repr(C)]
+pub(crate) struct CFRunLoopTimerContext {
+    pub(crate) version: cf::CFIndex,
+    pub(crate) info: *mut libc::c_void,
+    pub(crate) retain: Option<extern "C" fn(*const libc::c_void) -> *const libc::c_void>,
+    pub(crate) release: Option<extern "C" fn(*const libc::c_void)>,
+    pub(crate) copy_description: Option<extern "C" fn(*const libc::c_void) -> cf::CFStringRef>,
+}
+
+extern "C" {
+    pub(crate) fn CFRunLoopTimerCreate(
+        allocator: cf::CFAllocatorRef,
+        fire_date: cf::CFAbsoluteTime,
+        interval: cf::CFTimeInterval,
+        flags: libc::c_ulong,
+        order: cf::CFIndex,
+        callout: extern "C" fn(CFRunLoopTimerRef, *mut libc::c_void),
+        context: *mut CFRunLoopTimerContext,
+    ) -> CFRunLoopTimerRef;
+    pub(crate) fn CFRunLoopAddTimer(
+        rl: cf::CFRunLoopRef,
+        timer: CFRunLoopTimerRef,
+        mode: cf::CFStringRef,
+    );
+    pub(crate) fn CFRunLoopTimerInvalidate(timer: CFRunLoopTimerRef);
+    pub(crate) fn CFRunLoopCopyCurrentMode(rl: cf::CFRunLoopRef) -> cf::CFStringRef;
+    pub(crate) fn CFAbsoluteTimeGetCurrent() -> cf::CFAbsoluteTime;
+    fn CFRunLoopWakeUp(rl: cf::CFRunLoopRef);
+}
+
+extern "C" fn keep_alive(_timer: CFRunLoopTimerRef, _info: *mut libc::c_void) {}
+
+/// Work for [`RunLoopThread::perform_and_wait`], boxed as the info of a timer.
+type Task = Box<dyn FnOnce() + Send>;
+
+extern "C" fn perform(_timer: CFRunLoopTimerRef, info: *mut libc::c_void) {
+    let (task, done) =
+        unsafe { *Box::from_raw(info as *mut (Task, crossbeam_channel::Sender<()>)) };
+    task();
+    let _ = done.send(());
+}
+
+/// Run loop thread which can be shared by several watchers,
+/// see [`FsEventWatcher::set_run_loop_thread`](crate::FsEventWatcher::set_run_loop_thread).
+///
+/// The thread runs the loop in `kCFRunLoopDefaultMode` until the last watcher using it
+/// (and the last other reference) is dropped.
+pub struct RunLoopThread {
+    runloop: cf::CFRunLoopRef,
+    thread_handle: Option<thread::JoinHandle<()>>,
+}
+
+// The run loop ref is only used with thread-safe calls: scheduling streams,
+// `CFRunLoopIsWaiting` and `CFRunLoopStop`.
+unsafe impl Send for RunLoopThread {}
+unsafe impl Sync for RunLoopThread {}
+
+impl RunLoopThread {
+    pub fn spawn() -> Arc<RunLoopThread> {
+        let (rl_tx, rl_rx) = crossbeam_channel::bounded(1);
+        let thread_handle = thread::Builder::new()
+            .name("fsevent-dump shared loop".to_owned())
+            .spawn(move || unsafe {
+                let runloop = cf::CFRunLoopGetCurrent();
+                // A loop without sources returns immediately, and there are no streams
+                // scheduled until the first watcher starts, so add a timer which never fires.
+                let timer = CFRunLoopTimerCreate(
+                    cf::kCFAllocatorDefault,
+                    f64::MAX,
+                    0.0,
+                    0,
+                    0,
+                    keep_alive,
+                    ptr::null_mut(),
+                );
+                CFRunLoopAddTimer(runloop, timer, cf::kCFRunLoopDefaultMode);
+
+                rl_tx
+                    .send(CFSendWrapper(runloop))
+                    .expect("Unable to send runloop to the owner");
+                loop {
+                    match CFRunLoopRunInMode(cf::kCFRunLoopDefaultMode, 1.0e10, 0) {
+                        kCFRunLoopRunStopped | kCFRunLoopRunFinished => break,
+                        _ => {}
+                    }
+                }
+
+                CFRunLoopTimerInvalidate(timer);
+                cf::CFRelease(timer);
+            })
+            .expect("Unable to spawn runloop thread");
+
+        Arc::new(RunLoopThread {
+            runloop: rl_rx.recv().unwrap().0,
+            thread_handle: Some(thread_handle),
+        })
+    }
+
+    pub(crate) fn runloop(&self) -> cf::CFRunLoopRef {
+        self.runloop
+    }
+
+    /// Run `task` on the thread, between callbacks of the streams scheduled on it,
+    /// and wait for it to finish. Runs `task` directly if called on the thread itself.
+    pub(crate) fn perform_and_wait(&self, task: Task) {
+        unsafe {
+            if cf::CFRunLoopGetCurrent() == self.runloop {
+                task();
+                return;
+            }
+            let (done_tx, done_rx) = crossbeam_channel::bounded::<()>(1);
+            let mut context = CFRunLoopTimerContext {
+                version: 0,
+                info: Box::into_raw(Box::new((task, done_tx))) as *mut libc::c_void,
+                retain: None,
+                release: None,
+                copy_description: None,
+            };
+            // One-shot timer firing as soon as the loop gets to it.
+            let timer = CFRunLoopTimerCreate(
+                cf::kCFAllocatorDefault,
+                CFAbsoluteTimeGetCurrent(),
+                0.0,
+                0,
+                0,
+                perform,
+                &mut context,
+            );
+            CFRunLoopAddTimer(self.runloop, timer, cf::kCFRunLoopDefaultMode);
+            CFRunLoopWakeUp(self.runloop);
+            done_rx.recv().expect("shared run loop to perform the task");
+            cf::CFRelease(timer);
+        }
+    }
+}
+
+impl Drop for RunLoopThread {
+    fn drop(&mut self) {
+        unsafe {
+            while CFRunLoopIsWaiting(self.runloop) == 0 {
+                thread::yield_now();
+            }
+            cf::CFRunLoopStop(self.runloop);
+        }
+        if let Some(thread_handle) = self.thread_handle.take() {
+            thread_handle.join().expect("thread to shut down");
+        }
+        debug!("shared run loop stopped");
+    }
+}
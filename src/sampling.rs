@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::Event;
+use crate::StreamFlags;
+
+/// Limits the number of events forwarded per path per time window,
+/// see [`FsEventWatcher::set_sampling`](crate::FsEventWatcher::set_sampling).
+pub(crate) struct Sampler {
+    max_events: usize,
+    window: Duration,
+    paths: Mutex<HashMap<PathBuf, PathWindow>>,
+}
+
+/// Events of a single path in the current window.
+struct PathWindow {
+    start: Instant,
+    forwarded: usize,
+    /// Union of flags of the suppressed events.
+    suppressed_flags: StreamFlags,
+    suppressed: u64,
+    last_suppressed_id: u64,
+}
+
+impl Sampler {
+    pub(crate) fn new(max_events: usize, window: Duration) -> Sampler {
+        Sampler {
+            max_events,
+            window,
+            paths: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether the event should be forwarded, otherwise it is recorded
+    /// to be reported in a summary once the window ends.
+    pub(crate) fn admit(&self, path: &Path, flags: StreamFlags, id: u64) -> bool {
+        let mut paths = self.paths.lock().expect("lock not to be poisoned");
+        let window = paths.entry(path.to_owned()).or_insert_with(|| PathWindow {
+            start: Instant::now(),
+            forwarded: 0,
+            suppressed_flags: StreamFlags::empty(),
+            suppressed: 0,
+            last_suppressed_id: 0,
+        });
+        if window.forwarded < self.max_events {
+            window.forwarded += 1;
+            return true;
+        }
+        window.suppressed_flags |= flags;
+        window.suppressed += 1;
+        window.last_suppressed_id = id;
+        false
+    }
+
+    /// Summary events for paths whose windows ended, with
+    /// [`collapsed`](Event::collapsed) set to the number of suppressed events.
+    pub(crate) fn take_expired(&self) -> Vec<Event> {
+        let now = Instant::now();
+        let mut paths = self.paths.lock().expect("lock not to be poisoned");
+        let mut summaries = Vec::new();
+        paths.retain(|path, window| {
+            if now.duration_since(window.start) < self.window {
+                return true;
+            }
+            if window.suppressed != 0 {
+                let flags = window.suppressed_flags | StreamFlags::ITEM_MODIFIED;
+                summaries.push(Event {
+                    path: path.clone(),
+                    flags,
+                    raw_flags: flags.bits(),
+                    id: window.last_suppressed_id,
+                    data_change: None,
+                    metadata_change: None,
+                    inode: None,
+                    collapsed: window.suppressed,
+                    batch: None,
+                });
+            }
+            false
+        });
+        summaries.sort_by_key(|event| event.id);
+        summaries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_suppressed_events() {
+        let sampler = Sampler::new(2, Duration::from_secs(3600));
+        let path = Path::new("/a");
+        assert!(sampler.admit(path, StreamFlags::ITEM_CREATED, 1));
+        assert!(sampler.admit(path, StreamFlags::ITEM_MODIFIED, 2));
+        assert!(!sampler.admit(path, StreamFlags::INODE_META_MOD, 3));
+        assert!(!sampler.admit(path, StreamFlags::ITEM_MODIFIED, 4));
+        // Other paths have their own budget.
+        assert!(sampler.admit(Path::new("/b"), StreamFlags::ITEM_MODIFIED, 5));
+        // The window has not ended yet.
+        assert!(sampler.take_expired().is_empty());
+
+        let sampler = Sampler::new(1, Duration::ZERO);
+        assert!(sampler.admit(path, StreamFlags::ITEM_CREATED, 1));
+        assert!(!sampler.admit(path, StreamFlags::INODE_META_MOD, 2));
+        assert!(!sampler.admit(path, StreamFlags::ITEM_XATTR_MOD, 3));
+        assert!(sampler.admit(Path::new("/b"), StreamFlags::ITEM_MODIFIED, 4));
+        let summaries = sampler.take_expired();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].path, path);
+        assert_eq!(summaries[0].id, 3);
+        assert_eq!(summaries[0].collapsed, 2);
+        assert_eq!(
+            summaries[0].flags,
+            StreamFlags::INODE_META_MOD | StreamFlags::ITEM_XATTR_MOD | StreamFlags::ITEM_MODIFIED
+        );
+        // Windows are reset.
+        assert!(sampler.take_expired().is_empty());
+        assert!(sampler.admit(path, StreamFlags::ITEM_MODIFIED, 5));
+    }
+}
@@ -0,0 +1,66 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+/// Event counters, shared between the watcher and the stream callback.
+#[derive(Default, Debug)]
+pub(crate) struct Counters {
+    raw_events: AtomicU64,
+    forwarded_events: AtomicU64,
+    dropped_events: AtomicU64,
+    synthetic_events: AtomicU64,
+    handler_panics: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn raw(&self) {
+        self.raw_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn forwarded(&self) {
+        self.forwarded_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dropped(&self) {
+        self.dropped_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn synthetic(&self) {
+        self.synthetic_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn handler_panic(&self) {
+        self.handler_panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> WatcherStats {
+        WatcherStats {
+            raw_events: self.raw_events.load(Ordering::Relaxed),
+            forwarded_events: self.forwarded_events.load(Ordering::Relaxed),
+            dropped_events: self.dropped_events.load(Ordering::Relaxed),
+            synthetic_events: self.synthetic_events.load(Ordering::Relaxed),
+            handler_panics: self.handler_panics.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Event counts since the watcher was created.
+///
+/// `raw_events == forwarded_events + dropped_events`, modulo events being processed
+/// or held by the coalescing window while the snapshot is taken. Events generated
+/// by the watcher itself are counted in `synthetic_events` only.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WatcherStats {
+    /// Events delivered by FSEvents to the stream callback.
+    pub raw_events: u64,
+    /// Events passed to the event handler.
+    pub forwarded_events: u64,
+    /// Events filtered out by the watcher, or merged into another event.
+    pub dropped_events: u64,
+    /// Events generated by the watcher rather than FSEvents and passed to the event handler:
+    /// rescans requested after an id gap, a remount or exceeding the replay limit,
+    /// and summaries of sampled paths.
+    pub synthetic_events: u64,
+    /// Panics of the event handler caught by the watcher,
+    /// see [`on_handler_panic`](crate::FsEventWatcher::on_handler_panic).
+    pub handler_panics: u64,
+}
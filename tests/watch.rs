@@ -0,0 +1,197 @@
+//! Tests against real FSEvents, so only on macOS.
+
+#![cfg(target_os = "macos")]
+
+use std::env;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+use std::time::Instant;
+
+use crossbeam_channel::Receiver;
+use fsevent_dump::Event;
+use fsevent_dump::FsEventWatcher;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Empty directory unique to the test, canonical like paths reported by FSEvents.
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("fsevent-dump-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir.canonicalize().unwrap()
+}
+
+/// Wait for an event for `path` matching `predicate`.
+fn recv_for(rx: &Receiver<Event>, path: &Path, predicate: impl Fn(&Event) -> bool) -> Event {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let event = rx.recv_deadline(deadline).expect("event in time");
+        if event.path == path && predicate(&event) {
+            return event;
+        }
+    }
+}
+
+#[test]
+fn create_modify_remove() {
+    let dir = temp_dir("watch");
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let mut watcher = FsEventWatcher::new(tx);
+    watcher.set_latency(Duration::from_millis(10)).unwrap();
+    watcher.watch(&dir).unwrap();
+    watcher.wait_until_running(TIMEOUT).unwrap();
+
+    let file = dir.join("file");
+    fs::write(&file, b"a").unwrap();
+    recv_for(&rx, &file, Event::is_create);
+    fs::write(&file, b"ab").unwrap();
+    recv_for(&rx, &file, Event::is_modify);
+    fs::remove_file(&file).unwrap();
+    recv_for(&rx, &file, Event::is_remove);
+
+    let stats = watcher.stats();
+    assert!(stats.forwarded_events >= 3);
+    assert_eq!(stats.synthetic_events, 0);
+
+    drop(watcher);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn ignores_unwatched_paths() {
+    let dir = temp_dir("unwatched");
+    let watched = dir.join("watched");
+    fs::create_dir(&watched).unwrap();
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let mut watcher = FsEventWatcher::new(tx);
+    watcher.set_latency(Duration::from_millis(10)).unwrap();
+    watcher.watch(&watched).unwrap();
+    watcher.wait_until_running(TIMEOUT).unwrap();
+
+    fs::write(dir.join("other"), b"").unwrap();
+    let file = watched.join("file");
+    fs::write(&file, b"").unwrap();
+    recv_for(&rx, &file, Event::is_create);
+    assert!(rx.try_iter().all(|event| event.path.starts_with(&watched)));
+
+    drop(watcher);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn add_paths_then_start() {
+    let dir = temp_dir("start");
+    let (a, b) = (dir.join("a"), dir.join("b"));
+    fs::create_dir(&a).unwrap();
+    fs::create_dir(&b).unwrap();
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let mut watcher = FsEventWatcher::new(tx);
+    watcher.set_latency(Duration::from_millis(10)).unwrap();
+    watcher.add_path(&a).unwrap();
+    watcher.add_path(&b).unwrap();
+    assert!(!watcher.is_running());
+    watcher.start().unwrap();
+    watcher.wait_until_running(TIMEOUT).unwrap();
+
+    for dir in [&a, &b] {
+        let file = dir.join("file");
+        fs::write(&file, b"").unwrap();
+        recv_for(&rx, &file, Event::is_create);
+    }
+
+    watcher.stop();
+    assert!(!watcher.is_running());
+    assert_eq!(watcher.watched_paths().len(), 2);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn config_round_trip() {
+    let dir = temp_dir("config");
+    let (tx, _rx) = crossbeam_channel::unbounded();
+    let mut watcher = FsEventWatcher::new(tx);
+    watcher.set_latency(Duration::from_millis(250)).unwrap();
+    watcher.set_extended_data(true);
+    watcher.set_mark_self(true);
+    watcher.add_ignore_glob("**/*.tmp").unwrap();
+    watcher.set_include_paths(&[dir.join("src")]);
+    watcher.add_path(&dir).unwrap();
+    let config = watcher.to_config();
+
+    let json = serde_json::to_string(&config).unwrap();
+    let (tx, _rx) = crossbeam_channel::unbounded();
+    let restored = FsEventWatcher::from_config(&serde_json::from_str(&json).unwrap(), tx).unwrap();
+    assert!(restored.is_running());
+    assert_eq!(restored.to_config(), config);
+
+    drop(restored);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn is_watched() {
+    let dir = temp_dir("is-watched");
+    let (watched, file, other) = (dir.join("watched"), dir.join("file"), dir.join("other"));
+    fs::create_dir(&watched).unwrap();
+    fs::write(&file, b"").unwrap();
+    fs::create_dir(&other).unwrap();
+    let (tx, _rx) = crossbeam_channel::unbounded();
+    let mut watcher = FsEventWatcher::new(tx);
+    watcher.add_path(&watched).unwrap();
+    watcher.add_path(&file).unwrap();
+
+    assert!(watcher.is_watched(&watched));
+    assert!(watcher.is_watched(&file));
+    // Under a watched directory, whether it exists or not.
+    assert!(watcher.is_watched(&watched.join("a/b")));
+    assert!(!watcher.is_watched(&dir));
+    assert!(!watcher.is_watched(&other));
+    // The parent of a watched file is passed to FSEvents, but its other entries are not watched.
+    assert!(!watcher.is_watched(&dir.join("sibling")));
+
+    drop(watcher);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn snapshot_is_filtered_like_events() {
+    let dir = temp_dir("snapshot");
+    let tree = dir.join("tree");
+    fs::create_dir_all(tree.join("sub")).unwrap();
+    for name in ["file", ".hidden", "skip", "sub/deep"] {
+        fs::write(tree.join(name), b"").unwrap();
+    }
+    let link = dir.join("link");
+    symlink(&tree, &link).unwrap();
+
+    let (tx, _rx) = crossbeam_channel::unbounded();
+    let mut watcher = FsEventWatcher::new(tx);
+    watcher.set_follow_symlinks(false);
+    watcher.set_ignore_hidden(true);
+    watcher.set_max_depth(Some(1));
+    // Matched against the resolved path, like event paths.
+    watcher.add_ignore_glob("**/tree/skip").unwrap();
+    let mut snapshot = watcher.watch_with_snapshot(&link).unwrap();
+    snapshot.sort();
+    assert_eq!(
+        snapshot,
+        [link.clone(), link.join("file"), link.join("sub")]
+    );
+    drop(watcher);
+
+    let (tx, _rx) = crossbeam_channel::unbounded();
+    let mut watcher = FsEventWatcher::new(tx);
+    watcher.set_include_paths(&[tree.join("sub")]);
+    let mut snapshot = watcher.watch_with_snapshot(&tree).unwrap();
+    snapshot.sort();
+    assert_eq!(snapshot, [tree.join("sub"), tree.join("sub/deep")]);
+    drop(watcher);
+
+    fs::remove_dir_all(&dir).unwrap();
+}